@@ -12,14 +12,11 @@ if [[ -z "${cfg_bind_addr}" ]]; then
   exit 1
 fi
 
-# I didn't add DNS support so we resolve the addresses here before passing to
-# the app.
+# Bootstraps through the default list of well-known routers.
 
 args=(
+  crawl
   --bind "${cfg_bind_addr}"
-  -b "$(host router.bittorrent.com | head -n1 | awk '{print $NF}'):6881"
-  -b "$(host router.utorrent.com | head -n1 | awk '{print $NF}'):6881"
-  -b "$(host dht.transmissionbt.com | head -n1 | awk '{print $NF}'):6881"
 )
 
 exec cargo run -- "${args[@]}"
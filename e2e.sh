@@ -6,6 +6,7 @@ script_dir=$(dirname "$(realpath "$0")")
 cd "${script_dir}"
 
 args=(
+  crawl
   --id 35a35935f5226f7a6adcb84aa4da1b62c71023e1
   --concurrency 1
 
@@ -0,0 +1,12 @@
+#![no_main]
+
+//! Compact ip and port, as found in `values` and `nodes`.
+
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(addr) = NodeAddr::from_compact_node_id(data) {
+        assert_eq!(addr.to_compact_node_id(), data);
+    }
+});
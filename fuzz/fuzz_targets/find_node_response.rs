@@ -0,0 +1,24 @@
+#![no_main]
+
+//! Any datagram, decoded as answer to `find_node`. The nodes decode the same
+//! after encoding them again.
+
+use bendy::encoding::ToBencode;
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(res) = FindNodeResponse::from_bencode(data) else {
+        return;
+    };
+
+    let data = KrpcResponse {
+        tx_id: res.tx_id.clone(),
+        nodes: Some(res.nodes.clone()),
+        ..Default::default()
+    }
+    .to_bencode()
+    .expect("failed to encode");
+
+    assert_eq!(FindNodeResponse::from_bencode(&data).expect("failed to decode own encoding"), res);
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+//! Any datagram, as the receiver gets it. Decoding may fail but must not
+//! panic, and responses and errors decode the same after encoding them
+//! again.
+
+use bendy::encoding::ToBencode;
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+
+fn as_reply(inbound: KrpcInbound) -> Option<KrpcReply> {
+    match inbound {
+        KrpcInbound::Response(x) => Some(KrpcReply::Response(x)),
+        KrpcInbound::Error(x) => Some(KrpcReply::Error(x)),
+        KrpcInbound::Query(_) => None,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some(reply) = KrpcInbound::from_bencode(data).ok().and_then(as_reply) else {
+        return;
+    };
+
+    let data = reply.to_bencode().expect("failed to encode");
+    let decoded = KrpcInbound::from_bencode(&data).expect("failed to decode own encoding");
+
+    assert_eq!(as_reply(decoded), Some(reply));
+});
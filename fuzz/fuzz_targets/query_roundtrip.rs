@@ -0,0 +1,108 @@
+#![no_main]
+
+//! Every query we send decodes to what was sent, as the node we send it to
+//! sees it.
+
+use arbitrary::Arbitrary;
+use bendy::encoding::ToBencode;
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Query {
+    Ping {
+        tx_id: Vec<u8>,
+        id: [u8; ID_LEN_BYTES],
+    },
+
+    FindNode {
+        tx_id: Vec<u8>,
+        id: [u8; ID_LEN_BYTES],
+        target: [u8; ID_LEN_BYTES],
+    },
+
+    GetPeers {
+        tx_id: Vec<u8>,
+        id: [u8; ID_LEN_BYTES],
+        info_hash: [u8; ID_LEN_BYTES],
+    },
+
+    AnnouncePeer {
+        tx_id: Vec<u8>,
+        id: [u8; ID_LEN_BYTES],
+        info_hash: [u8; ID_LEN_BYTES],
+        port: Option<u16>,
+        token: Vec<u8>,
+    },
+}
+
+fuzz_target!(|query: Query| {
+    let (query, expected) = match query {
+        Query::Ping { tx_id, id } => (
+            KrpcQuery::Ping(PingRequest {
+                tx_id: TxId::new(tx_id.clone()),
+                node_id_self: NodeId::new(id),
+            }),
+            KrpcIncomingQuery {
+                tx_id: TxId::new(tx_id),
+                method_name: "ping".into(),
+                node_id: Some(NodeId::new(id)),
+                ..Default::default()
+            },
+        ),
+
+        Query::FindNode { tx_id, id, target } => (
+            KrpcQuery::FindNode(FindNodeRequest {
+                tx_id: TxId::new(tx_id.clone()),
+                node_id_self: NodeId::new(id),
+                node_id_target: NodeId::new(target),
+            }),
+            KrpcIncomingQuery {
+                tx_id: TxId::new(tx_id),
+                method_name: "find_node".into(),
+                node_id: Some(NodeId::new(id)),
+                target: Some(NodeId::new(target)),
+                ..Default::default()
+            },
+        ),
+
+        Query::GetPeers { tx_id, id, info_hash } => (
+            KrpcQuery::GetPeers(GetPeersRequest {
+                tx_id: TxId::new(tx_id.clone()),
+                node_id_self: NodeId::new(id),
+                info_hash: NodeId::new(info_hash),
+            }),
+            KrpcIncomingQuery {
+                tx_id: TxId::new(tx_id),
+                method_name: "get_peers".into(),
+                node_id: Some(NodeId::new(id)),
+                info_hash: Some(NodeId::new(info_hash)),
+                ..Default::default()
+            },
+        ),
+
+        Query::AnnouncePeer { tx_id, id, info_hash, port, token } => (
+            KrpcQuery::AnnouncePeer(AnnouncePeerRequest {
+                tx_id: TxId::new(tx_id.clone()),
+                node_id_self: NodeId::new(id),
+                info_hash: NodeId::new(info_hash),
+                port,
+                token: token.clone(),
+            }),
+            KrpcIncomingQuery {
+                tx_id: TxId::new(tx_id),
+                method_name: "announce_peer".into(),
+                node_id: Some(NodeId::new(id)),
+                info_hash: Some(NodeId::new(info_hash)),
+                port: Some(port.unwrap_or(0)),
+                implied_port: port.is_none(),
+                token: Some(token),
+                ..Default::default()
+            },
+        ),
+    };
+
+    let data = query.to_bencode().expect("failed to encode");
+
+    assert_eq!(KrpcInbound::from_bencode(&data).expect("failed to decode"), KrpcInbound::Query(expected));
+});
@@ -0,0 +1,69 @@
+#![no_main]
+
+//! Every answer we send decodes to what was sent, as the node we send it to
+//! sees it.
+
+use arbitrary::Arbitrary;
+use bendy::encoding::ToBencode;
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+use std::net::Ipv4Addr;
+
+/// Id, ip and port of a node.
+type CompactNode = ([u8; ID_LEN_BYTES], [u8; 4], u16);
+
+#[derive(Debug, Arbitrary)]
+enum Reply {
+    Response {
+        tx_id: Vec<u8>,
+        id: Option<[u8; ID_LEN_BYTES]>,
+        nodes: Option<Vec<CompactNode>>,
+        values: Option<Vec<([u8; 4], u16)>>,
+        token: Option<Vec<u8>>,
+    },
+
+    Error {
+        tx_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+fuzz_target!(|reply: Reply| {
+    let reply = match reply {
+        Reply::Response { tx_id, id, nodes, values, token } => KrpcReply::Response(KrpcResponse {
+            tx_id: TxId::new(tx_id),
+            node_id: id.map(NodeId::new),
+            nodes: nodes.map(|nodes| {
+                nodes
+                    .into_iter()
+                    .map(|(id, ip, port)| Node {
+                        id: NodeId::new(id),
+                        addr: NodeAddr::new(Ipv4Addr::from(ip), port),
+                    })
+                    .collect()
+            }),
+            values: values.map(|values| {
+                values
+                    .into_iter()
+                    .map(|(ip, port)| NodeAddr::new(Ipv4Addr::from(ip), port))
+                    .collect()
+            }),
+            token,
+        }),
+
+        Reply::Error { tx_id, code, message } => {
+            KrpcReply::Error(KrpcError::new(TxId::new(tx_id), code, message))
+        },
+    };
+
+    let data = reply.to_bencode().expect("failed to encode");
+
+    let decoded = match KrpcInbound::from_bencode(&data).expect("failed to decode") {
+        KrpcInbound::Response(x) => KrpcReply::Response(x),
+        KrpcInbound::Error(x) => KrpcReply::Error(x),
+        KrpcInbound::Query(x) => panic!("decoded as query: {:?}", x),
+    };
+
+    assert_eq!(decoded, reply);
+});
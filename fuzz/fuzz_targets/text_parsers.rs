@@ -0,0 +1,26 @@
+#![no_main]
+
+//! Addresses, ids and blocklists, as given on the command line, in config
+//! files and in blocklist files.
+
+use kademliar::blocklist::*;
+use kademliar::bootstrap::*;
+use kademliar::krpc::*;
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+fuzz_target!(|data: &str| {
+    if let Ok(addr) = NodeAddr::from_str(data) {
+        assert_eq!(NodeAddr::from_str(&addr.to_string()).expect("failed to parse own output"), addr);
+    }
+
+    if let Ok(node) = BootstrapNode::from_str(data) {
+        assert_eq!(BootstrapNode::from_str(&node.to_string()).expect("failed to parse own output"), node);
+    }
+
+    if let Ok(id) = NodeId::from_hex(data) {
+        assert_eq!(NodeId::from_hex(&id.to_string()).expect("failed to parse own output"), id);
+    }
+
+    let _ = BlocklistRules::from_str(data);
+});
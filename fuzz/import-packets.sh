@@ -0,0 +1,19 @@
+#!/bin/sh
+# Adds the packets received in a JSON log to the seed corpus of the
+# decoders. Capture one with e.g.
+#
+#   kademliar crawl --log-format json --log-level kademliar::krpc=debug 2> packets.json
+#
+set -eu
+
+log=${1:?usage: import-packets.sh <log>}
+corpus=$(dirname "$0")/corpus
+
+jq -R -r 'fromjson? | .fields.data_hex // empty' "$log" | while read -r hex; do
+    name=$(printf '%s' "$hex" | xxd -r -p | sha1sum | cut -d ' ' -f 1)
+
+    for target in krpc_inbound find_node_response; do
+        mkdir -p "$corpus/$target"
+        printf '%s' "$hex" | xxd -r -p > "$corpus/$target/$name"
+    done
+done
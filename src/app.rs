@@ -1,329 +1,275 @@
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use itertools::Itertools;
-use std::collections::HashMap;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::sync::atomic::{Ordering, AtomicU16};
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, RwLock};
-use tokio::time::{sleep, Duration};
-use tracing::{debug, error};
-
-use crate::krpc::*;
-
-/// Joins the Bittorrent DHT network and looks up the closest nodes to itself.
-#[derive(Debug, Parser, Clone)]
-struct AppArgs {
-    /// How many nodes to keep per k-bucket.
-    #[arg(short = 'k', default_value_t = 8)]
-    k: usize,
-
-    /// How many requests to send at once.
-    #[arg(short = 'j', long, default_value_t = 3)]
-    concurrency: usize,
-
-    /// Timeout for requests, in seconds.
-    #[arg(long, default_value_t = 5_000)]
-    timeout_ms: u64,
-
-    /// Bootstrap node in ipv4:port format. Can be specified multiple times.
-    #[arg(short, long, required = true, num_args = 1..)]
-    bootstrap_node: Vec<String>,
-
-    /// UDP address to bind to, in ipv4:port format. Use port 0 for random
-    /// port.
-    #[arg(long)]
-    bind: String,
-
-    /// ID to use, instead of randomly generating a new one. As hex string.
-    #[arg(long)]
-    id: Option<String>,
+use clap::Args;
+use futures::future::{join_all, FutureExt};
+use futures::stream::StreamExt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+
+use crate::bootstrap::*;
+use crate::cli::*;
+use crate::dht::*;
+use crate::events::*;
+use crate::output::*;
+use crate::shutdown::*;
+
+/// Arguments of the `crawl` command.
+#[derive(Debug, Args, Clone)]
+pub struct AppArgs {
+    #[command(flatten)]
+    pub net: NetworkArgs,
+
+    /// How many times to retry a bootstrap source (resolving its nodes
+    /// again), when none of its nodes responded.
+    #[arg(long, default_value_t = 2, env = "KADEMLIAR_BOOTSTRAP_RETRIES")]
+    pub bootstrap_retries: u32,
+
+    /// File to keep the routing table in between runs. Its nodes are tried
+    /// before the bootstrap nodes.
+    #[arg(long, env = "KADEMLIAR_NODES_FILE")]
+    pub nodes_file: Option<PathBuf>,
+
+    /// Writes every newly discovered node to this file as soon as it's
+    /// found, one per line. Use `-` for stdout, which the routing table is
+    /// printed to as well once the crawl is done.
+    #[arg(long, env = "KADEMLIAR_DISCOVERED")]
+    pub discovered: Option<PathBuf>,
+
+    /// Format of the nodes written to `--discovered`.
+    #[arg(long, value_enum, default_value_t = DiscoveryFormat::Ndjson, env = "KADEMLIAR_DISCOVERED_FORMAT")]
+    pub discovered_format: DiscoveryFormat,
 }
 
+/// Joins the network and looks up the nodes closest to our own id, then
+/// prints the routing table. Runs on a [`Dht`], so it answers queries while
+/// it's at it.
 #[derive(Debug)]
 pub struct App {
-    k: usize,
-    concurrency: usize,
-    timeout_ms: u64,
-    id: NodeId,
-    bootstrap_nodes: Vec<NodeAddr>,
-    sock: Arc<dyn KrpcSocket>,
-    requests: Arc<RwLock<HashMap<TxId, KrpcRequest>>>,
-    next_tx_id: AtomicU16,
+    dht: Dht,
+    bootstrap_nodes: Vec<BootstrapNode>,
+    bootstrap_retries: u32,
+    nodes_file: Option<NodesFile>,
+    output: OutputFormat,
+    discovered: Option<DiscoveryWriter>,
 }
 
 impl App {
-    pub async fn main() -> Result<()> {
-        let args = AppArgs::parse();
-
+    pub async fn main(args: AppArgs) -> Result<()> {
         debug!(?args);
 
-        let id = Self::parse_id(&args)?;
-        let bootstrap_nodes = Self::parse_bootstrap_nodes(&args.bootstrap_node)?;
-        let bind_addr = NodeAddr::from_str(&args.bind)?;
-        let sock = UdpSocket::bind(&bind_addr.to_string()).await?;
+        let dht = args.net.start_dht().await?;
 
-        debug!(bind_addr = ?sock.local_addr());
+        let discovered = match &args.discovered {
+            Some(path) => Some(DiscoveryWriter::create(path, args.discovered_format)?),
+            None => None,
+        };
 
         let app = App {
-            k: args.k,
-            concurrency: args.concurrency,
-            timeout_ms: args.timeout_ms,
-            id,
-            bootstrap_nodes,
-            sock: Arc::new(KrpcSocketImpl(sock)),
-            requests: Arc::new(RwLock::new(HashMap::new())),
-            next_tx_id: AtomicU16::new(0x6161),
+            dht,
+            bootstrap_nodes: args.net.parse_bootstrap_nodes()?,
+            bootstrap_retries: args.bootstrap_retries,
+            nodes_file: args.nodes_file.as_ref().map(NodesFile::new),
+            output: args.net.output,
+            discovered,
         };
 
-        let (sender_tx, sender_rx) = mpsc::channel::<KrpcMessage>(1024);
-        let (main_tx, main_rx) = mpsc::channel::<KrpcMessage>(1024);
-
-        app.main_internal(sender_tx, sender_rx, main_tx, main_rx).await
+        app.run(wait_for_signal()).await
     }
 
-    fn parse_id(args: &AppArgs) -> Result<NodeId> {
-        let Some(s) = &args.id else {
-            return Ok(NodeId::random(ID_LEN_BYTES));
-        };
+    /// Crawls until it's done, or fails with [`Interrupted`] once `shutdown`
+    /// completes. Writes the routing table either way.
+    async fn run(mut self, shutdown: impl Future<Output = Result<Signal>>) -> Result<()> {
+        debug!(?self);
 
-        let id = NodeId::from_hex(s)?;
+        let discovered = self.discovered.take();
 
-        if id.len() != ID_LEN_BYTES {
-            return Err(anyhow!(
-                "id is of invalid length (expected {}, got {})",
-                ID_LEN_BYTES, id.len()
-            ));
-        }
+        let result = tokio::select! {
+            result = self.crawl(discovered) => result,
 
-        Ok(id)
-    }
+            signal = shutdown => {
+                let signal = signal?;
 
-    fn parse_bootstrap_nodes(values: &[String]) -> Result<Vec<NodeAddr>> {
-        let mut nodes = vec![];
+                info!(%signal, "exit");
 
-        for value in values.iter() {
-            nodes.push(NodeAddr::from_str(value)?);
+                Err(Interrupted(signal).into())
+            },
+        };
+
+        // Whatever is still in flight is dropped.
+        self.dht.shutdown().await;
+
+        match &result {
+            Err(e) if !e.is::<Interrupted>() => {},
+            _ => self.write_state().await?,
         }
 
-        Ok(nodes)
+        result
     }
 
-    async fn main_internal(
-        &self,
-        sender_tx: mpsc::Sender<KrpcMessage>,
-        sender_rx: mpsc::Receiver<KrpcMessage>,
-        main_tx: mpsc::Sender<KrpcMessage>,
-        mut main_rx: mpsc::Receiver<KrpcMessage>,
-    ) -> Result<()> {
-        debug!(?self);
+    /// Bootstraps, then looks up the nodes closest to our own id. Writes the
+    /// nodes every response brings up to `discovered`.
+    async fn crawl(&self, mut discovered: Option<DiscoveryWriter>) -> Result<()> {
+        let started_at = Instant::now();
+        let mut events = Box::pin(self.dht.events());
 
-        let sender = KrpcSender {
-            requests: self.requests.clone(),
-            concurrency: self.concurrency,
-            sock: self.sock.clone(),
-            sender_rx,
-            main_tx: main_tx.clone(),
-        };
+        let lookup = async {
+            self.bootstrap().await?;
+            self.dht.bootstrap().await?;
 
-        let receiver = KrpcReceiver {
-            sock: self.sock.clone(),
-            main_tx: main_tx.clone(),
+            Ok::<_, anyhow::Error>(())
         };
 
-        sender.spawn();
-        receiver.spawn();
+        tokio::pin!(lookup);
 
-        for node_addr in self.bootstrap_nodes.iter() {
-            self.request_closest_nodes_to_self(
-                node_addr.clone(),
-                sender_tx.clone()
-            ).await?;
-        }
+        let result = loop {
+            tokio::select! {
+                result = &mut lookup => break result,
 
-        let mut closest_distance: Option<Distance> = None;
-        let mut k_buckets: HashMap<usize, Vec<Node>> = HashMap::new();
-
-        while let Some(msg) = main_rx.recv().await {
-            debug!(?msg, "main: recv");
-
-            match msg {
-                KrpcMessage::Response(res) => {
-                    let Some(_req) = self.requests
-                        .write()
-                        .await
-                        .remove(&res.tx_id) else {
-                            error!(
-                                ?res,
-                                "response with no matching tx_id --> ignore"
-                            );
-
-                            continue;
-                        };
-
-                    // TODO: Check that the response came from the node we sent
-                    // the request to?
-
-                    debug!(tx_id = ?res.tx_id, ?res, "response");
-
-                    for node in res.nodes.into_iter() {
-                        let distance = node.id.distance_to(&self.id);
-                        let k_bucket_index = distance.lcp();
-
-                        debug!(tx_id = ?res.tx_id, ?distance, ?node, "node");
-
-                        let k_bucket = match k_buckets.get_mut(&k_bucket_index) {
-                            Some(x) => x,
-                            None => {
-                                k_buckets.insert(k_bucket_index, vec![]);
-                                k_buckets.get_mut(&k_bucket_index).unwrap()
-                            },
-                        };
-
-                        if k_bucket.len() >= self.k {
-                            debug!(
-                                tx_id = ?res.tx_id,
-                                "k-bucket is full --> ignore"
-                            );
-
-                            continue;
-                        }
-
-                        let distance = node.id.distance_to(&self.id);
-                        let is_closer = match &closest_distance {
-                            Some(value) => distance < *value,
-                            None => true,
-                        };
-
-                        if is_closer {
-                            debug!(
-                                tx_id = ?res.tx_id,
-                                "node is closer --> query"
-                            );
-
-                            closest_distance = Some(distance);
-
-                            self.request_closest_nodes_to_self(
-                                node.addr.clone(),
-                                sender_tx.clone()
-                            ).await?;
-                        }
-
-                        if !k_bucket.iter().any(|x| *x == node) {
-                            k_bucket.push(node);
-                        }
-                    }
-                },
+                Some(event) = events.next() => self.write_discovered(&mut discovered, event),
+            }
+        };
 
-                KrpcMessage::SendSuccess(tx_id) => {
-                    let timeout_ms = self.timeout_ms;
-                    let main_tx = main_tx.clone();
+        // Responses that came in last are still queued.
+        while let Some(Some(event)) = events.next().now_or_never() {
+            self.write_discovered(&mut discovered, event);
+        }
 
-                    tokio::spawn(async move {
-                        sleep(Duration::from_millis(timeout_ms)).await;
+        result?;
 
-                        main_tx
-                            .send(KrpcMessage::ResponseTimeout(tx_id))
-                            .await
-                            .unwrap();
-                    });
-                },
+        let elapsed = started_at.elapsed();
+        let stats = self.dht.stats();
 
-                KrpcMessage::SendError(tx_id) => {
-                    if let Some(_req) = self.requests
-                        .write()
-                        .await
-                        .remove(&tx_id)
-                    {
-                        error!(?tx_id, "send error --> remove");
-                    }
-                },
+        info!(
+            ?stats,
+            ?elapsed,
+            qps = format!("{:.1}", stats.queries_per_second(elapsed)),
+            "done"
+        );
 
-                KrpcMessage::ResponseTimeout(tx_id) => {
-                    if let Some(_req) = self.requests
-                        .write()
-                        .await
-                        .remove(&tx_id)
-                    {
-                        error!(?tx_id, "timeout --> remove");
-                    }
-                },
+        Ok(())
+    }
 
-                KrpcMessage::Exit => {
-                    debug!("exit");
-                    return Ok(());
-                },
+    /// Writes the nodes a response brought up, other than blocked ones and
+    /// ourselves. Stops writing them once that fails, e.g. because whoever
+    /// read them went away, but keeps crawling.
+    fn write_discovered(&self, discovered: &mut Option<DiscoveryWriter>, event: DhtEvent) {
+        let (Some(writer), DhtEvent::NodesReceived { src, nodes }) = (discovered.as_mut(), event) else {
+            return;
+        };
 
-                _ => {},
+        let now = SystemTime::now();
+
+        for node in nodes.iter() {
+            if node.id == *self.dht.id() || self.dht.blocklist().blocks_node(node) {
+                continue;
             }
 
-            if self.requests.read().await.is_empty() {
-                debug!("done");
+            if let Err(e) = writer.write(node, Some(&src.id), &src.addr, now) {
+                error!(err = ?e, "discovered: write failed --> stop writing");
+                *discovered = None;
+                return;
+            }
+        }
+    }
 
-                for key in k_buckets.keys().sorted() {
-                    let bucket = k_buckets.get(key).unwrap();
-                    println!("k-bucket {}: {:#?}", key, bucket);
-                }
+    /// Saves the routing table to the nodes file and writes it to stdout.
+    /// Saving comes first, so it isn't lost if stdout went away.
+    async fn write_state(&self) -> Result<()> {
+        let routing_table = self.dht.routing_table().await;
 
-                return Ok(());
-            }
+        if let Some(nodes_file) = &self.nodes_file {
+            nodes_file.save(routing_table.nodes())?;
         }
 
-        Ok(())
+        write_routing_table(
+            &mut std::io::stdout().lock(),
+            &routing_table,
+            self.output
+        )
     }
 
-    async fn request_closest_nodes_to_self(
-        &self,
-        dst: NodeAddr,
-        sender_tx: mpsc::Sender<KrpcMessage>,
-    ) -> Result<()> {
-        let tx_id = self.create_tx_id();
-        let req = KrpcRequest {
-            dst,
-            payload: FindNodeRequest {
-                tx_id: tx_id.clone(),
-                node_id_self: self.id.clone(),
-                node_id_target: self.id.clone(),
+    /// The persisted nodes first, then the bootstrap nodes.
+    fn bootstrap_chain(&self) -> BootstrapChain {
+        let persisted = match self.nodes_file.as_ref().map(|x| x.load()) {
+            Some(Ok(nodes)) => nodes,
+
+            Some(Err(e)) => {
+                error!(err = ?e, "bootstrap: failed to load persisted nodes");
+                vec![]
             },
-            in_progress: false,
-        };
 
-        self
-            .requests
-            .write()
-            .await
-            .insert(tx_id.clone(), req);
+            None => vec![],
+        };
 
-        sender_tx.send(KrpcMessage::Request(tx_id)).await?;
+        let sources = vec![
+            BootstrapSource {
+                name: "persisted nodes",
+                nodes: persisted.into_iter().map(|x| x.addr.into()).collect(),
+            },
+            BootstrapSource {
+                name: "bootstrap nodes",
+                nodes: self.bootstrap_nodes.clone(),
+            },
+        ];
 
-        Ok(())
+        BootstrapChain::new(sources, self.bootstrap_retries)
     }
 
-    fn create_tx_id(&self) -> TxId {
-        TxId::from_u16(
-            self.next_tx_id.fetch_add(1, Ordering::Relaxed)
-        )
+    /// Asks every address of the next bootstrap source for the nodes closest
+    /// to us, until one of them responds. Fails once every source was tried.
+    async fn bootstrap(&self) -> Result<()> {
+        let mut chain = self.bootstrap_chain();
+        let blocklist = self.dht.blocklist();
+
+        while let Some(source) = chain.next_source() {
+            let mut addrs = resolve_all(&source.nodes).await;
+
+            addrs.retain(|x| !blocklist.blocks_ip(&x.ip));
+
+            if addrs.is_empty() {
+                error!(source = source.name, "bootstrap: no node to query --> next source");
+                continue;
+            }
+
+            info!(source = source.name, nodes = addrs.len(), "bootstrap");
+
+            let results = join_all(addrs.into_iter().map(|addr| {
+                self.dht.find_node_at(addr, self.dht.id().clone())
+            })).await;
+
+            if results.iter().any(Result::is_ok) {
+                return Ok(());
+            }
+
+            info!("no bootstrap node responded --> retry");
+        }
+
+        Err(anyhow!(
+            "bootstrap failed, no node responded from any of: {}",
+            chain.describe()
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use crate::blocklist::*;
+    use crate::krpc::*;
+    use crate::vnet::*;
 
     #[derive(Debug, Default)]
     struct Setup {
         bootstrap_nodes: Option<Vec<NodeAddr>>,
+        bootstrap_retries: u32,
         node_id: Option<NodeId>,
-    }
-
-    #[allow(dead_code)]
-    #[derive(Debug)]
-    struct SetupResult {
-        requests: Arc<RwLock<HashMap<TxId, KrpcRequest>>>,
-        task: tokio::task::JoinHandle<Result<()>>,
-        sender_rx: mpsc::Receiver<KrpcMessage>,
-        main_tx: mpsc::Sender<KrpcMessage>,
+        blocklist: Arc<Blocklist>,
+        nodes_file: Option<PathBuf>,
     }
 
     impl Setup {
@@ -336,59 +282,96 @@ mod tests {
             self
         }
 
+        fn bootstrap_retries(mut self, value: u32) -> Self {
+            self.bootstrap_retries = value;
+            self
+        }
+
         fn node_id(mut self, value: NodeId) -> Self {
             self.node_id = Some(value);
             self
         }
 
-        fn execute(self) -> SetupResult {
+        fn blocklist(mut self, value: Blocklist) -> Self {
+            self.blocklist = Arc::new(value);
+            self
+        }
+
+        fn nodes_file(mut self, value: PathBuf) -> Self {
+            self.nodes_file = Some(value);
+            self
+        }
+
+        /// Crawls on `network`. Bootstraps off an address nobody is bound
+        /// to by default.
+        fn execute(self, network: &VirtualNetwork) -> App {
             with_tracing();
 
-            let (sender_tx, sender_rx) = mpsc::channel::<KrpcMessage>(1024);
-            let (_, dummy_sender_rx) = mpsc::channel::<KrpcMessage>(1024);
-            let (main_tx, main_rx) = mpsc::channel::<KrpcMessage>(1024);
-            let main_tx_cloned = main_tx.clone();
-
-            let requests = Arc::new(RwLock::new(HashMap::new()));
-            let requests_cloned = requests.clone();
-
-            let bootstrap_nodes = self.bootstrap_nodes.unwrap_or(vec![
-                NodeAddr::from_str("127.0.0.1:1000").unwrap()
-            ]);
-
-            let node_id = self.node_id.unwrap_or_else(||
-                NodeId::random(ID_LEN_BYTES)
-            );
-
-            let task = tokio::spawn(async move {
-                let app = App {
-                    k: 8,
-                    concurrency: 999, // doesn't matter
-                    timeout_ms: 1_000,
-                    id: node_id,
-                    bootstrap_nodes,
-                    sock: Arc::new(KrpcSocketStub),
-                    requests: requests_cloned,
-                    next_tx_id: AtomicU16::new(0),
-                };
-
-                app.main_internal(
-                    sender_tx,
-                    dummy_sender_rx,
-                    main_tx_cloned,
-                    main_rx
-                ).await
-            });
-
-            SetupResult {
-                requests,
-                task,
-                sender_rx,
-                main_tx,
+            let bootstrap_nodes = self.bootstrap_nodes
+                .unwrap_or(vec![NodeAddr::from_str("10.0.1.1:6881").unwrap()])
+                .into_iter()
+                .map(BootstrapNode::from)
+                .collect();
+
+            let dht = Dht::builder(DhtConfig {
+                id: self.node_id,
+                timeout_ms: 1_000,
+                blocklist: self.blocklist,
+                ..Default::default()
+            })
+            .socket(network.bind_next())
+            .build()
+            .unwrap();
+
+            App {
+                dht,
+                bootstrap_nodes,
+                bootstrap_retries: self.bootstrap_retries,
+                nodes_file: self.nodes_file.map(NodesFile::new),
+                output: OutputFormat::Text,
+                discovered: None,
             }
         }
     }
 
+    /// Starts a node with each of `ids` on `network`, each bootstrapping off
+    /// the first one.
+    async fn start_nodes(network: &VirtualNetwork, ids: &[&str]) -> Vec<Dht> {
+        let mut nodes: Vec<Dht> = vec![];
+
+        for id in ids.iter() {
+            let sock = network.bind_next();
+            let addr = sock.addr().clone();
+
+            let dht = Dht::builder(DhtConfig {
+                id: Some(NodeId::from_hex(id).unwrap()),
+                bootstrap_nodes: match nodes.is_empty() {
+                    true => vec![],
+                    false => vec![first_node().into()],
+                },
+                ..Default::default()
+            })
+            .socket(sock)
+            .build()
+            .unwrap();
+
+            if !nodes.is_empty() {
+                dht.bootstrap().await.unwrap();
+            }
+
+            debug!(%addr, "test: node started");
+
+            nodes.push(dht);
+        }
+
+        nodes
+    }
+
+    /// Address of the first node [`start_nodes`] starts.
+    fn first_node() -> NodeAddr {
+        NodeAddr::new(FIRST_VIRTUAL_IP, VIRTUAL_PORT)
+    }
+
     fn with_tracing() {
         use tracing_subscriber::FmtSubscriber;
 
@@ -399,100 +382,157 @@ mod tests {
         let _ = tracing::subscriber::set_global_default(subscriber);
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn stops_after_bootstrapping_when_all_requests_failed() -> Result<()> {
-        let mut s = Setup::new()
+        let network = VirtualNetwork::new();
+        let app = Setup::new()
             .bootstrap_nodes(vec![
-                NodeAddr::from_str("127.0.0.1:1000").unwrap(),
-                NodeAddr::from_str("127.0.0.1:1001").unwrap(),
+                NodeAddr::from_str("10.0.1.1:6881").unwrap(),
+                NodeAddr::from_str("10.0.1.2:6881").unwrap(),
             ])
-            .execute();
+            .execute(&network);
 
-        let mut requests_sent = vec![];
+        let err = app.crawl(None).await.unwrap_err();
 
-        while let Some(msg) = s.sender_rx.recv().await {
-            match msg {
-                KrpcMessage::Request(tx_id) => {
-                    requests_sent.push(tx_id.clone());
-                    s.main_tx.send(KrpcMessage::SendError(tx_id)).await?;
-                },
-                _ => {},
-            }
-        }
+        assert!(err.to_string().starts_with("bootstrap failed"), "{}", err);
+        assert_eq!(app.dht.stats().queries_sent, 2);
 
-        assert_eq!(2, requests_sent.len());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bootstraps_again_when_no_bootstrap_node_responded() -> Result<()> {
+        let network = VirtualNetwork::new();
+        let app = Setup::new()
+            .bootstrap_retries(1)
+            .execute(&network);
+
+        let err = app.crawl(None).await.unwrap_err();
+
+        assert!(err.to_string().starts_with("bootstrap failed"), "{}", err);
+        assert_eq!(app.dht.stats().queries_sent, 2);
 
-        let _ = s.task.await?;
         Ok(())
     }
 
     #[tokio::test]
-    async fn queries_closer_nodes_on_response() -> Result<()> {
-        let mut s = Setup::new()
+    async fn finds_nodes_closest_to_itself() -> Result<()> {
+        let network = VirtualNetwork::new();
+
+        let _nodes = start_nodes(&network, &[
+            "0000000000000000000000000000000000000000",
+            "8000000000000000000000000000000000000000",
+            "f000000000000000000000000000000000000000",
+            "ffffffffff000000000000000000000000000000",
+            "ffffffffffffffffffff00000000000000000000",
+        ]).await;
+
+        let app = Setup::new()
             .node_id(NodeId::from_hex("ffffffffffffffffffffffffffffffffffffffff").unwrap())
-            .execute();
-
-        let mut requests_sent = vec![];
-
-        while let Some(msg) = s.sender_rx.recv().await {
-            match msg {
-                KrpcMessage::Request(tx_id) if tx_id == TxId::from_u16(0) => {
-                    requests_sent.push(tx_id.clone());
-
-                    s.main_tx.send(KrpcMessage::SendSuccess(tx_id.clone())).await?;
-                    s.main_tx.send(KrpcMessage::Response(FindNodeResponse {
-                        tx_id,
-                        nodes: vec![Node {
-                            id: NodeId::from_hex("ffffffffff000000000000000000000000000000").unwrap(),
-                            addr: NodeAddr::from_str("127.0.0.1:2000").unwrap(),
-                        }]
-                    })).await?;
-                },
+            .bootstrap_nodes(vec![first_node()])
+            .execute(&network);
 
-                KrpcMessage::Request(tx_id) if tx_id == TxId::from_u16(1) => {
-                    requests_sent.push(tx_id.clone());
-
-                    s.main_tx.send(KrpcMessage::SendSuccess(tx_id.clone())).await?;
-                    s.main_tx.send(KrpcMessage::Response(FindNodeResponse {
-                        tx_id,
-                        nodes: vec![Node {
-                            id: NodeId::from_hex("ffffffffffffffffffff00000000000000000000").unwrap(),
-                            addr: NodeAddr::from_str("127.0.0.1:2001").unwrap(),
-                        }]
-                    })).await?;
-                },
+        app.crawl(None).await?;
 
-                KrpcMessage::Request(tx_id) if tx_id == TxId::from_u16(2) => {
-                    requests_sent.push(tx_id.clone());
+        let routing_table = app.dht.routing_table().await;
 
-                    // not closer, so this won't lead to another query
+        assert_eq!(routing_table.len(), 5);
+        assert!(routing_table.contains(&NodeId::from_hex("ffffffffffffffffffff00000000000000000000").unwrap()));
 
-                    s.main_tx.send(KrpcMessage::SendSuccess(tx_id.clone())).await?;
-                    s.main_tx.send(KrpcMessage::Response(FindNodeResponse {
-                        tx_id,
-                        nodes: vec![Node {
-                            id: NodeId::from_hex("fffffffffffffff0000000000000000000000000").unwrap(),
-                            addr: NodeAddr::from_str("127.0.0.1:2002").unwrap(),
-                        }]
-                    })).await?;
-                },
+        Ok(())
+    }
 
-                KrpcMessage::Request(tx_id) => {
-                    requests_sent.push(tx_id.clone());
-                    s.main_tx.send(KrpcMessage::SendError(tx_id.clone())).await?;
-                },
+    #[tokio::test]
+    async fn writes_discovered_nodes_other_than_blocked_ones() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kademliar-discovered-{}.ndjson", std::process::id()));
+        let network = VirtualNetwork::new();
+
+        let _nodes = start_nodes(&network, &[
+            "0000000000000000000000000000000000000000",
+            "ff00000000000000000000000000000000000000",
+            "1000000000000000000000000000000000000000",
+        ]).await;
+
+        let app = Setup::new()
+            .node_id(NodeId::from_hex("2000000000000000000000000000000000000000").unwrap())
+            .blocklist(Blocklist::new("ff".parse()?))
+            .bootstrap_nodes(vec![first_node()])
+            .execute(&network);
+
+        let discovered = DiscoveryWriter::create(&path, DiscoveryFormat::Ndjson)?;
+
+        app.crawl(Some(discovered)).await?;
+
+        let discovered = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
 
-                _ => {},
+        let mut ids: Vec<&str> = discovered
+            .lines()
+            .map(|x| &x[7..47]) // {"id":"<hex>",...
+            .collect();
+
+        ids.sort();
+
+        assert_eq!(ids, vec![
+            "0000000000000000000000000000000000000000",
+            "1000000000000000000000000000000000000000",
+        ], "{}", discovered);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_crawling_when_writing_discovered_nodes_fails() -> Result<()> {
+        struct BrokenPipe;
+
+        impl std::io::Write for BrokenPipe {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
         }
 
-        assert_eq!(3, requests_sent.len());
+        let network = VirtualNetwork::new();
+        let _nodes = start_nodes(&network, &[
+            "0000000000000000000000000000000000000000",
+            "1000000000000000000000000000000000000000",
+        ]).await;
+
+        let app = Setup::new()
+            .bootstrap_nodes(vec![first_node()])
+            .execute(&network);
+
+        let discovered = DiscoveryWriter::new(Box::new(BrokenPipe), DiscoveryFormat::Ndjson)?;
+
+        app.crawl(Some(discovered)).await?;
+
+        assert_eq!(app.dht.routing_table().await.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancels_requests_and_stops_on_signal() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kademliar-nodes-{}.txt", std::process::id()));
+        let network = VirtualNetwork::new();
+        let app = Setup::new()
+            .nodes_file(path.clone())
+            .execute(&network);
+
+        let dht = app.dht.clone();
+
+        let err = app.run(async { Ok(Signal::Terminate) }).await.unwrap_err();
+
+        assert_eq!(err.downcast_ref(), Some(&Interrupted(Signal::Terminate)));
+        assert!(dht.in_flight().await.is_empty());
 
-        // TODO: Assert the k-buckets. Either grap the k_buckets directly from
-        // App or add a callback (listener) for when the main loop exits (where
-        // it currently dumps the k_buckets to stdout).
+        // The routing table found so far is saved, empty as it is.
+        assert!(std::fs::read_to_string(&path)?.is_empty());
+        std::fs::remove_file(&path)?;
 
-        let _ = s.task.await?;
         Ok(())
     }
 }
@@ -0,0 +1,300 @@
+use anyhow::{bail, Context, Result};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::krpc::*;
+
+/// IPv4 range in CIDR notation. A bare address is a /32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    network: u32,
+    prefix_len: u32,
+}
+
+impl Ipv4Cidr {
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+
+        u32::from(*ip) & mask == self.network & mask
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix_len) = match value.split_once('/') {
+            Some((ip, prefix_len)) => (ip, prefix_len),
+            None => (value, "32"),
+        };
+
+        let ip = Ipv4Addr::from_str(ip)
+            .with_context(|| format!("invalid ip: {:?}", ip))?;
+
+        let prefix_len = u32::from_str(prefix_len)
+            .with_context(|| format!("invalid prefix length: {:?}", prefix_len))?;
+
+        if prefix_len > 32 {
+            bail!("invalid prefix length: {:?}", prefix_len);
+        }
+
+        Ok(Self { network: u32::from(ip), prefix_len })
+    }
+}
+
+/// Leading hex digits of a node id. Can be of odd length, in which case the
+/// last digit is matched against the high nibble of the byte that follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdPrefix {
+    bytes: Vec<u8>,
+    nibble: Option<u8>,
+}
+
+impl IdPrefix {
+    pub fn matches(&self, id: &NodeId) -> bool {
+        let id = id.as_slice();
+
+        id.starts_with(&self.bytes) && match self.nibble {
+            Some(nibble) => id.get(self.bytes.len()).is_some_and(|x| x >> 4 == nibble),
+            None => true,
+        }
+    }
+}
+
+impl FromStr for IdPrefix {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.is_empty()
+            || value.len() > ID_LEN_HEX
+            || !value.chars().all(|x| x.is_ascii_hexdigit())
+        {
+            bail!("invalid id prefix: {:?}", value);
+        }
+
+        let digit = |x: u8| (x as char).to_digit(16).unwrap_or_default() as u8;
+        let mut digits = value.as_bytes().chunks_exact(2);
+
+        let bytes = digits
+            .by_ref()
+            .map(|x| digit(x[0]) << 4 | digit(x[1]))
+            .collect();
+
+        Ok(Self {
+            bytes,
+            nibble: digits.remainder().first().map(|x| digit(*x)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlocklistRules {
+    pub ranges: Vec<Ipv4Cidr>,
+    pub id_prefixes: Vec<IdPrefix>,
+}
+
+impl BlocklistRules {
+    pub fn len(&self) -> usize {
+        self.ranges.len() + self.id_prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl FromStr for BlocklistRules {
+    type Err = anyhow::Error;
+
+    /// One CIDR or hex id prefix per line. Everything after a `#` is a
+    /// comment.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut rules = Self::default();
+
+        for (index, line) in value.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let result = if line.contains('.') {
+                Ipv4Cidr::from_str(line).map(|x| rules.ranges.push(x))
+            } else {
+                IdPrefix::from_str(line).map(|x| rules.id_prefixes.push(x))
+            };
+
+            result.with_context(|| format!("line {}", index + 1))?;
+        }
+
+        Ok(rules)
+    }
+}
+
+/// IP ranges and node id prefixes we never talk to. Shared between the
+/// receiver, the routing table and the lookups, and can be reloaded from its
+/// file while they use it.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    path: Option<PathBuf>,
+    rules: RwLock<BlocklistRules>,
+}
+
+impl Blocklist {
+    pub fn new(rules: BlocklistRules) -> Self {
+        Self {
+            path: None,
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let blocklist = Self {
+            path: Some(path.as_ref().to_path_buf()),
+            rules: RwLock::default(),
+        };
+
+        blocklist.reload()?;
+        Ok(blocklist)
+    }
+
+    /// Reads the file again. Keeps the current rules if that fails. Returns
+    /// the number of rules.
+    pub fn reload(&self) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(self.rules.read().unwrap().len());
+        };
+
+        let rules: BlocklistRules = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read blocklist: {:?}", path))?
+            .parse()
+            .with_context(|| format!("failed to parse blocklist: {:?}", path))?;
+
+        let len = rules.len();
+
+        info!(?path, rules = len, "blocklist: loaded");
+
+        *self.rules.write().unwrap() = rules;
+        Ok(len)
+    }
+
+    pub fn blocks_ip(&self, ip: &Ipv4Addr) -> bool {
+        self.rules
+            .read()
+            .unwrap()
+            .ranges
+            .iter()
+            .any(|x| x.contains(ip))
+    }
+
+    pub fn blocks_id(&self, id: &NodeId) -> bool {
+        self.rules
+            .read()
+            .unwrap()
+            .id_prefixes
+            .iter()
+            .any(|x| x.matches(id))
+    }
+
+    pub fn blocks_node(&self, node: &Node) -> bool {
+        self.blocks_ip(&node.addr.ip) || self.blocks_id(&node.id)
+    }
+}
+
+/// Reloads `blocklist` whenever the process gets a SIGHUP.
+pub async fn reload_on_hangup(blocklist: Arc<Blocklist>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        if let Err(e) = blocklist.reload() {
+            error!(err = ?e, "blocklist: reload failed");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_cidr_ranges() -> Result<()> {
+        let range = Ipv4Cidr::from_str("10.1.0.0/16")?;
+
+        assert!(range.contains(&"10.1.2.3".parse()?));
+        assert!(!range.contains(&"10.2.0.1".parse()?));
+
+        assert!(Ipv4Cidr::from_str("0.0.0.0/0")?.contains(&"1.2.3.4".parse()?));
+        assert!(Ipv4Cidr::from_str("1.2.3.4")?.contains(&"1.2.3.4".parse()?));
+        assert!(!Ipv4Cidr::from_str("1.2.3.4")?.contains(&"1.2.3.5".parse()?));
+
+        assert!(Ipv4Cidr::from_str("1.2.3.4/33").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_rules_with_comments() -> Result<()> {
+        let rules = BlocklistRules::from_str("
+            # known bad ranges
+            10.0.0.0/8
+            192.168.1.1   # single address
+
+            ABC  # spammy ids
+        ")?;
+
+        assert_eq!(rules.ranges.len(), 2);
+        assert_eq!(rules.id_prefixes, vec![IdPrefix::from_str("abc")?]);
+
+        let err = BlocklistRules::from_str("10.0.0.0/8\nxyz").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_nodes_by_ip_or_id_prefix() -> Result<()> {
+        let blocklist = Blocklist::new("10.0.0.0/8\nabc".parse()?);
+
+        let node = |id: &str, addr: &str| Node {
+            id: NodeId::from_hex(id).unwrap(),
+            addr: NodeAddr::from_str(addr).unwrap(),
+        };
+
+        assert!(blocklist.blocks_node(&node("0000000000000000000000000000000000000000", "10.1.1.1:1")));
+        assert!(blocklist.blocks_node(&node("abcd000000000000000000000000000000000000", "1.1.1.1:1")));
+        assert!(!blocklist.blocks_node(&node("ab00000000000000000000000000000000000000", "1.1.1.1:1")));
+
+        let even = Blocklist::new("AB".parse()?);
+
+        assert!(even.blocks_node(&node("ab00000000000000000000000000000000000000", "1.1.1.1:1")));
+        assert!(!even.blocks_node(&node("ac00000000000000000000000000000000000000", "1.1.1.1:1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reloads_from_file_and_keeps_rules_on_error() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kademliar-blocklist-{}", std::process::id()));
+
+        std::fs::write(&path, "10.0.0.0/8\n")?;
+        let blocklist = Blocklist::load(&path)?;
+        assert!(blocklist.blocks_ip(&"10.0.0.1".parse()?));
+
+        std::fs::write(&path, "10.0.0.0/8\n11.0.0.0/8\n")?;
+        assert_eq!(blocklist.reload()?, 2);
+        assert!(blocklist.blocks_ip(&"11.0.0.1".parse()?));
+
+        std::fs::write(&path, "not an entry\n")?;
+        assert!(blocklist.reload().is_err());
+        assert!(blocklist.blocks_ip(&"11.0.0.1".parse()?));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
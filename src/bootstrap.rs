@@ -0,0 +1,298 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::join_all;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, error};
+
+use crate::krpc::*;
+
+/// Well-known routers, used when no bootstrap nodes are configured.
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "dht.libtorrent.org:25401",
+];
+
+/// Bootstrap node in `host:port` format, where the host is either a hostname
+/// or an ipv4 address. Hostnames are resolved every time the node is used,
+/// so a bootstrap that failed can pick up changed DNS records.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BootstrapNode {
+    pub host: String,
+    pub port: u16,
+}
+
+impl BootstrapNode {
+    /// Every ipv4 address the host resolves to.
+    pub async fn resolve(&self) -> Result<Vec<NodeAddr>> {
+        let addrs: Vec<NodeAddr> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("failed to resolve: {}", self))?
+            .filter_map(|x| NodeAddr::try_from(x).ok())
+            .collect();
+
+        if addrs.is_empty() {
+            bail!("no ipv4 address for: {}", self);
+        }
+
+        debug!(node = %self, ?addrs, "bootstrap: resolved");
+
+        Ok(addrs)
+    }
+}
+
+/// Resolves all `nodes` at once. Nodes that fail to resolve are logged and
+/// skipped. Addresses are returned in the order of the nodes, without
+/// duplicates.
+pub async fn resolve_all(nodes: &[BootstrapNode]) -> Vec<NodeAddr> {
+    let results = join_all(nodes.iter().map(|x| x.resolve())).await;
+    let mut addrs: Vec<NodeAddr> = vec![];
+
+    for (node, result) in nodes.iter().zip(results) {
+        match result {
+            Ok(resolved) => {
+                for addr in resolved {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            },
+
+            Err(e) => error!(%node, err = ?e, "bootstrap: resolve failed"),
+        }
+    }
+
+    addrs
+}
+
+/// Named set of bootstrap nodes, e.g. the nodes persisted by the last run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapSource {
+    pub name: &'static str,
+    pub nodes: Vec<BootstrapNode>,
+}
+
+/// Sources to bootstrap from, in order. Each source is tried up to
+/// `1 + retries` times before moving on to the next one.
+#[derive(Debug, Clone)]
+pub struct BootstrapChain {
+    sources: Vec<BootstrapSource>,
+    retries: u32,
+    current: usize,
+    attempt: u32,
+}
+
+impl BootstrapChain {
+    /// Sources without nodes are skipped.
+    pub fn new(sources: Vec<BootstrapSource>, retries: u32) -> Self {
+        Self {
+            sources: sources.into_iter().filter(|x| !x.nodes.is_empty()).collect(),
+            retries,
+            current: 0,
+            attempt: 0,
+        }
+    }
+
+    /// The source to try next, or `None` once every source failed.
+    pub fn next_source(&mut self) -> Option<&BootstrapSource> {
+        if self.attempt > self.retries {
+            self.current += 1;
+            self.attempt = 0;
+        }
+
+        self.attempt += 1;
+        self.sources.get(self.current)
+    }
+
+    /// Names of all sources, for error messages.
+    pub fn describe(&self) -> String {
+        if self.sources.is_empty() {
+            return "no bootstrap sources".into();
+        }
+
+        self.sources
+            .iter()
+            .map(|x| format!("{} ({} nodes)", x.name, x.nodes.len()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// Nodes from the routing table, kept between runs so the next one can
+/// bootstrap without the well-known routers. One `<hex id> <ip:port>` per
+/// line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodesFile {
+    pub path: PathBuf,
+}
+
+impl NodesFile {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self { path: path.as_ref().to_path_buf() }
+    }
+
+    /// Nodes from the file. A missing file has none.
+    pub fn load(&self) -> Result<Vec<Node>> {
+        let data = match std::fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("failed to read: {:?}", self.path)),
+        };
+
+        let mut nodes = vec![];
+
+        for (index, line) in data.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((id, addr)) = line.split_once(' ') else {
+                bail!("invalid node on line {}: {:?}", index + 1, line);
+            };
+
+            nodes.push(Node {
+                id: NodeId::from_hex(id)?,
+                addr: NodeAddr::from_str(addr.trim())?,
+            });
+        }
+
+        Ok(nodes)
+    }
+
+    pub fn save<'a>(&self, nodes: impl IntoIterator<Item = &'a Node>) -> Result<()> {
+        let data: String = nodes
+            .into_iter()
+            .map(|x| format!("{} {}\n", x.id, x.addr))
+            .collect();
+
+        std::fs::write(&self.path, data)
+            .with_context(|| format!("failed to write: {:?}", self.path))
+    }
+}
+
+impl From<NodeAddr> for BootstrapNode {
+    fn from(value: NodeAddr) -> Self {
+        Self {
+            host: value.ip.to_string(),
+            port: value.port,
+        }
+    }
+}
+
+impl FromStr for BootstrapNode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((host, port)) = value.rsplit_once(':') else {
+            return Err(anyhow!("invalid host/port: {:?}", value));
+        };
+
+        if host.is_empty() {
+            bail!("invalid host: {:?}", value);
+        }
+
+        let port = u16::from_str(port)
+            .with_context(|| format!("invalid port: {:?}", port))?;
+
+        Ok(Self { host: host.to_string(), port })
+    }
+}
+
+impl std::fmt::Display for BootstrapNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_host_and_port() -> Result<()> {
+        assert_eq!(
+            BootstrapNode::from_str("router.bittorrent.com:6881")?,
+            BootstrapNode { host: "router.bittorrent.com".into(), port: 6881 }
+        );
+
+        assert!(BootstrapNode::from_str("router.bittorrent.com").is_err());
+        assert!(BootstrapNode::from_str(":6881").is_err());
+        assert!(BootstrapNode::from_str("localhost:http").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tries_sources_in_order_with_retries() {
+        let source = |name, port| BootstrapSource {
+            name,
+            nodes: vec![BootstrapNode { host: "127.0.0.1".into(), port }],
+        };
+
+        let mut chain = BootstrapChain::new(
+            vec![
+                source("persisted nodes", 1),
+                BootstrapSource { name: "empty", nodes: vec![] },
+                source("bootstrap nodes", 2),
+            ],
+            1,
+        );
+
+        let mut names = vec![];
+
+        while let Some(source) = chain.next_source() {
+            names.push(source.name);
+        }
+
+        assert_eq!(names, vec![
+            "persisted nodes",
+            "persisted nodes",
+            "bootstrap nodes",
+            "bootstrap nodes",
+        ]);
+
+        assert_eq!(chain.describe(), "persisted nodes (1 nodes), bootstrap nodes (1 nodes)");
+    }
+
+    #[test]
+    fn saves_and_loads_nodes() -> Result<()> {
+        let file = NodesFile::new(
+            std::env::temp_dir().join(format!("kademliar-nodes-{}", std::process::id()))
+        );
+
+        assert_eq!(file.load()?, vec![]);
+
+        let nodes = vec![Node {
+            id: NodeId::from_hex("35a35935f5226f7a6adcb84aa4da1b62c71023e1")?,
+            addr: NodeAddr::from_str("1.2.3.4:6881")?,
+        }];
+
+        file.save(nodes.iter())?;
+        assert_eq!(file.load()?, nodes);
+
+        std::fs::remove_file(&file.path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolves_hostnames_and_ips() -> Result<()> {
+        let nodes = vec![
+            BootstrapNode::from_str("localhost:6881")?,
+            BootstrapNode::from_str("127.0.0.2:6881")?,
+            BootstrapNode::from_str("does-not-exist.invalid:6881")?,
+        ];
+
+        assert_eq!(
+            resolve_all(&nodes).await,
+            vec![
+                NodeAddr::from_str("127.0.0.1:6881")?,
+                NodeAddr::from_str("127.0.0.2:6881")?,
+            ]
+        );
+
+        Ok(())
+    }
+}
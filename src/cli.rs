@@ -0,0 +1,655 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tracing::{debug, info};
+
+use crate::app::*;
+use crate::blocklist::*;
+use crate::bootstrap::*;
+use crate::config::*;
+use crate::control::*;
+use crate::dht::*;
+use crate::krpc::*;
+use crate::logging::*;
+use crate::output::*;
+use crate::peers::*;
+use crate::ratelimit::*;
+use crate::serve::*;
+use crate::shutdown::*;
+
+/// Bittorrent DHT client.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML file with settings, see the README. Flags and environment
+    /// variables take precedence over it.
+    #[arg(long, global = true, env = "KADEMLIAR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parses `args` and fills in what wasn't given from the `--config`
+    /// file. Exits on invalid flags, like [`Parser::parse`].
+    pub fn parse_with_config<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().get_matches_from(args);
+        let mut cli = Self::from_arg_matches(&matches)?;
+
+        if let (Some(path), Some((_, matches))) = (&cli.config, matches.subcommand()) {
+            cli.command.apply_config(&ConfigFile::load(path)?, matches);
+        }
+
+        Ok(cli)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Pings a single node and prints its id.
+    Ping {
+        /// Node to ping, in host:port format.
+        addr: String,
+
+        #[command(flatten)]
+        net: NetworkArgs,
+    },
+
+    /// Looks up the k closest nodes to a target id.
+    FindNode {
+        /// Target id, as hex string. Random if not set.
+        #[arg(long)]
+        target: Option<String>,
+
+        #[command(flatten)]
+        net: NetworkArgs,
+    },
+
+    /// Looks up peers for an info hash.
+    GetPeers {
+        /// Info hash, as hex string.
+        info_hash: String,
+
+        #[command(flatten)]
+        net: NetworkArgs,
+    },
+
+    /// Announces that we're a peer for an info hash to the closest nodes.
+    Announce {
+        /// Info hash, as hex string.
+        info_hash: String,
+
+        /// Port we accept peers on. The nodes use the source port of the
+        /// announcement if not set.
+        #[arg(long)]
+        port: Option<u16>,
+
+        #[command(flatten)]
+        net: NetworkArgs,
+    },
+
+    /// Joins the network and looks up the closest nodes to itself, then
+    /// prints the routing table.
+    Crawl(AppArgs),
+
+    /// Joins the network and stays on it until SIGINT or SIGTERM. Answers
+    /// queries, keeps the routing table fresh and announces itself for the
+    /// given info hashes. Prints the routing table on exit.
+    Serve(ServeArgs),
+
+    /// Sends a JSON-RPC request to a node started with `serve
+    /// --control-socket`, and prints the result as JSON.
+    Ctl {
+        /// Control socket of the node.
+        #[arg(short, long)]
+        socket: PathBuf,
+
+        /// One of routing_table, requests, find_node, ping, get_peers,
+        /// announce or shutdown.
+        method: String,
+
+        /// Parameters as JSON object, e.g. '{"addr": "1.2.3.4:6881"}'.
+        params: Option<String>,
+    },
+
+    /// Works with the `--config` file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+impl Command {
+    /// Sets everything `matches` only has the default for from `file`.
+    pub fn apply_config(&mut self, file: &ConfigFile, matches: &ArgMatches) {
+        match self {
+            Command::Ping { net, .. }
+            | Command::FindNode { net, .. }
+            | Command::GetPeers { net, .. }
+            | Command::Announce { net, .. } => file.apply_network(matches, net),
+
+            Command::Crawl(args) => file.apply_crawl(matches, args),
+            Command::Serve(args) => file.apply_serve(matches, args),
+            Command::Ctl { .. } | Command::Config(_) => {},
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validates the settings from the file, environment variables and
+    /// defaults, and prints them as TOML.
+    Check,
+}
+
+/// Settings shared by every command that talks to the network.
+#[derive(Debug, Args, Clone)]
+pub struct NetworkArgs {
+    /// How many nodes to keep per k-bucket.
+    #[arg(short = 'k', default_value_t = 8, env = "KADEMLIAR_K")]
+    pub k: usize,
+
+    /// How many requests to send at once.
+    #[arg(short = 'j', long, default_value_t = 3, env = "KADEMLIAR_CONCURRENCY")]
+    pub concurrency: usize,
+
+    /// Timeout for requests, in milliseconds.
+    #[arg(long, default_value_t = 5_000, env = "KADEMLIAR_TIMEOUT_MS")]
+    pub timeout_ms: u64,
+
+    /// How many times to retry a request that timed out, before giving up on
+    /// the node.
+    #[arg(long, default_value_t = 0, env = "KADEMLIAR_RETRIES")]
+    pub retries: u32,
+
+    /// Delay before the first retry, in milliseconds. Doubles with each
+    /// retry.
+    #[arg(long, default_value_t = 500, env = "KADEMLIAR_RETRY_BACKOFF_MS")]
+    pub retry_backoff_ms: u64,
+
+    /// Maximum number of packets to send per second. Unlimited if not set.
+    #[arg(long, env = "KADEMLIAR_MAX_PACKETS_PER_SEC")]
+    pub max_packets_per_sec: Option<u32>,
+
+    /// Maximum number of bytes to send per second. Unlimited if not set.
+    #[arg(long, env = "KADEMLIAR_MAX_BYTES_PER_SEC")]
+    pub max_bytes_per_sec: Option<u32>,
+
+    /// Maximum number of packets to accept per second from a single IP.
+    #[arg(long, default_value_t = 50, env = "KADEMLIAR_MAX_INBOUND_PACKETS_PER_SEC")]
+    pub max_inbound_packets_per_sec: u32,
+
+    /// How long to block an IP that keeps going over its limit, in seconds.
+    #[arg(long, default_value_t = 60, env = "KADEMLIAR_INBOUND_BLOCK_SECS")]
+    pub inbound_block_secs: u64,
+
+    /// How many source IPs to apply the inbound limit to. The least recently
+    /// seen ones are forgotten first.
+    #[arg(long, default_value_t = 10_000, env = "KADEMLIAR_MAX_INBOUND_SOURCES")]
+    pub max_inbound_sources: usize,
+
+    /// How many info hashes to store peers for. Announcements for new ones
+    /// are ignored beyond that.
+    #[arg(long, default_value_t = 10_000, env = "KADEMLIAR_MAX_INFO_HASHES")]
+    pub max_info_hashes: usize,
+
+    /// How many peers to store per info hash. New ones replace the oldest
+    /// beyond that.
+    #[arg(long, default_value_t = 1_000, env = "KADEMLIAR_MAX_PEERS_PER_INFO_HASH")]
+    pub max_peers_per_info_hash: usize,
+
+    /// Bootstrap node in host:port format, where host is a hostname or an
+    /// ipv4 address. Can be specified multiple times. Replaces the default
+    /// list of well-known routers.
+    #[arg(short, long, num_args = 1.., value_delimiter = ',', default_values = DEFAULT_BOOTSTRAP_NODES, env = "KADEMLIAR_BOOTSTRAP_NODE")]
+    pub bootstrap_node: Vec<String>,
+
+    /// UDP address to bind to, in ipv4:port format. Use port 0 for random
+    /// port.
+    #[arg(long, default_value = "0.0.0.0:0", env = "KADEMLIAR_BIND")]
+    pub bind: String,
+
+    /// ID to use, instead of randomly generating a new one. As hex string.
+    #[arg(long, env = "KADEMLIAR_ID")]
+    pub id: Option<String>,
+
+    /// File with IP ranges (CIDR) and hex node id prefixes to never talk
+    /// to, one per line. Reloaded on SIGHUP.
+    #[arg(long, env = "KADEMLIAR_BLOCKLIST")]
+    pub blocklist: Option<PathBuf>,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, env = "KADEMLIAR_OUTPUT")]
+    pub output: OutputFormat,
+}
+
+impl NetworkArgs {
+    pub fn parse_id(&self) -> Result<NodeId> {
+        match &self.id {
+            Some(value) => parse_hex_id("id", value),
+            None => Ok(NodeId::random(ID_LEN_BYTES)),
+        }
+    }
+
+    /// Checks the settings that are otherwise only parsed once they're used,
+    /// including the blocklist file.
+    pub fn validate(&self) -> Result<()> {
+        self.check_settings()?;
+
+        if let Some(path) = &self.blocklist {
+            Blocklist::load(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Like [`NetworkArgs::validate`], but leaves the blocklist file to
+    /// [`NetworkArgs::load_blocklist`].
+    fn check_settings(&self) -> Result<()> {
+        // Nothing would ever be sent with a concurrency of 0, and neither
+        // buckets nor lookups work without room for a node.
+        for (name, value) in [
+            ("k", self.k as u64),
+            ("concurrency", self.concurrency as u64),
+            ("timeout_ms", self.timeout_ms),
+        ] {
+            if value == 0 {
+                return Err(anyhow!("{} must be at least 1", name));
+            }
+        }
+
+        // The token bucket can't refill at a rate of 0. There's no limit to
+        // begin with when they're unset.
+        for (name, value) in [
+            ("max_packets_per_sec", self.max_packets_per_sec),
+            ("max_bytes_per_sec", self.max_bytes_per_sec),
+        ] {
+            if value == Some(0) {
+                return Err(anyhow!("{} must be at least 1, or unset for no limit", name));
+            }
+        }
+
+        // Unlike the outbound ones, the inbound limit is always on.
+        if self.max_inbound_packets_per_sec == 0 {
+            return Err(anyhow!("max_inbound_packets_per_sec must be at least 1"));
+        }
+
+        self.parse_id()?;
+        self.parse_bootstrap_nodes()?;
+
+        NodeAddr::from_str(&self.bind).context("invalid bind address")?;
+
+        Ok(())
+    }
+
+    pub fn parse_bootstrap_nodes(&self) -> Result<Vec<BootstrapNode>> {
+        let mut nodes = vec![];
+
+        for value in self.bootstrap_node.iter() {
+            nodes.push(BootstrapNode::from_str(value)?);
+        }
+
+        Ok(nodes)
+    }
+
+    /// Loads the blocklist and reloads it on SIGHUP. Without a file, SIGHUP
+    /// is left alone.
+    pub fn load_blocklist(&self) -> Result<Arc<Blocklist>> {
+        let Some(path) = &self.blocklist else {
+            return Ok(Arc::new(Blocklist::default()));
+        };
+
+        let blocklist = Arc::new(Blocklist::load(path)?);
+
+        tokio::spawn(reload_on_hangup(blocklist.clone()));
+
+        Ok(blocklist)
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        RateLimit {
+            packets_per_sec: self.max_packets_per_sec,
+            bytes_per_sec: self.max_bytes_per_sec,
+        }
+    }
+
+    pub fn inbound_limit(&self) -> InboundLimit {
+        InboundLimit {
+            packets_per_sec: self.max_inbound_packets_per_sec,
+            max_sources: self.max_inbound_sources,
+            block_for: Duration::from_secs(self.inbound_block_secs),
+            ..Default::default()
+        }
+    }
+
+    pub fn peer_limit(&self) -> PeerLimit {
+        PeerLimit {
+            max_info_hashes: self.max_info_hashes,
+            max_peers_per_info_hash: self.max_peers_per_info_hash,
+        }
+    }
+
+    pub async fn bind(&self) -> Result<KrpcSocketImpl> {
+        let bind_addr = NodeAddr::from_str(&self.bind)?;
+        let sock = UdpSocket::bind(&bind_addr.to_string()).await?;
+
+        debug!(bind_addr = ?sock.local_addr());
+
+        Ok(KrpcSocketImpl(sock))
+    }
+
+    /// Binds the socket and starts a node.
+    pub async fn start_dht(&self) -> Result<Dht> {
+        self.check_settings()?;
+
+        let config = DhtConfig {
+            k: self.k,
+            concurrency: self.concurrency,
+            timeout_ms: self.timeout_ms,
+            id: Some(self.parse_id()?),
+            retries: self.retries,
+            retry_backoff_ms: self.retry_backoff_ms,
+            bootstrap_nodes: self.parse_bootstrap_nodes()?,
+            rate_limit: self.rate_limit(),
+            inbound_limit: self.inbound_limit(),
+            peer_limit: self.peer_limit(),
+            blocklist: self.load_blocklist()?,
+        };
+
+        Dht::builder(config).socket(self.bind().await?).build()
+    }
+}
+
+/// Settings of the `serve` command.
+#[derive(Debug, Args, Clone)]
+pub struct ServeArgs {
+    /// Info hash to announce and keep announcing, as hex string. Can be
+    /// specified multiple times.
+    #[arg(long, value_delimiter = ',', env = "KADEMLIAR_ANNOUNCE")]
+    pub announce: Vec<String>,
+
+    /// Port we accept peers on. The nodes use the source port of the
+    /// announcement if not set.
+    #[arg(long, env = "KADEMLIAR_PORT")]
+    pub port: Option<u16>,
+
+    /// How often to refresh buckets without good nodes, in seconds.
+    #[arg(long, default_value_t = 60, env = "KADEMLIAR_REFRESH_SECS")]
+    pub refresh_secs: u64,
+
+    /// How often to ping nodes that haven't responded for a while, in
+    /// seconds.
+    #[arg(long, default_value_t = 5 * 60, env = "KADEMLIAR_PING_SECS")]
+    pub ping_secs: u64,
+
+    /// How often to announce again, in seconds.
+    #[arg(long, default_value_t = 15 * 60, env = "KADEMLIAR_REPUBLISH_SECS")]
+    pub republish_secs: u64,
+
+    /// Unix socket to accept JSON-RPC requests on, see `ctl`.
+    #[arg(long, env = "KADEMLIAR_CONTROL_SOCKET")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on at `/metrics`, in ip:port
+    /// format. Best kept local.
+    #[arg(long, env = "KADEMLIAR_METRICS")]
+    pub metrics: Option<String>,
+
+    /// Address to serve the HTTP API on, in ip:port format.
+    #[cfg(feature = "http")]
+    #[arg(long, env = "KADEMLIAR_HTTP")]
+    pub http: Option<String>,
+
+    #[command(flatten)]
+    pub net: NetworkArgs,
+}
+
+impl ServeArgs {
+    pub fn config(&self) -> Result<ServeConfig> {
+        // Each of them is the period of a timer, which can't be zero.
+        for (name, value) in [
+            ("refresh_secs", self.refresh_secs),
+            ("ping_secs", self.ping_secs),
+            ("republish_secs", self.republish_secs),
+        ] {
+            if value == 0 {
+                return Err(anyhow!("{} must be at least 1", name));
+            }
+        }
+
+        Ok(ServeConfig {
+            refresh_every: Duration::from_secs(self.refresh_secs),
+            ping_every: Duration::from_secs(self.ping_secs),
+            republish_every: Duration::from_secs(self.republish_secs),
+            announce: self
+                .announce
+                .iter()
+                .map(|x| parse_hex_id("info hash", x))
+                .collect::<Result<_>>()?,
+            port: self.port,
+        })
+    }
+}
+
+/// Node id or info hash given as hex string.
+pub fn parse_hex_id(name: &str, value: &str) -> Result<NodeId> {
+    let id = NodeId::from_hex(value)?;
+
+    if id.len() != ID_LEN_BYTES {
+        return Err(anyhow!(
+            "{} is of invalid length (expected {}, got {})",
+            name, ID_LEN_BYTES, id.len()
+        ));
+    }
+
+    Ok(id)
+}
+
+async fn serve(args: ServeArgs) -> Result<()> {
+    let config = args.config()?;
+    let dht = args.net.start_dht().await?;
+    let mut tasks = vec![];
+
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+
+    let control = match &args.control_socket {
+        Some(path) => Some(ControlServer::bind(path, dht.clone(), shutdown_tx)?),
+        None => None,
+    };
+
+    let control_path = control.as_ref().map(|x| x.path().to_path_buf());
+    tasks.extend(control.map(|x| x.spawn()));
+
+    if let Some(addr) = &args.metrics {
+        tasks.push(crate::metrics::spawn(addr, dht.clone()).await?);
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = &args.http {
+        tasks.push(crate::http::spawn(addr, dht.clone()).await?);
+    }
+
+    info!(id = %dht.id(), "serve: start");
+
+    // The signal, if it wasn't the control socket that asked to shut down.
+    let result = Daemon::new(dht.clone(), config)?
+        .run(async {
+            tokio::select! {
+                result = wait_for_signal() => result.map(Some),
+                _ = shutdown_rx.recv() => Ok(None),
+            }
+        })
+        .await;
+
+    for task in tasks.iter() {
+        task.abort();
+    }
+
+    if let Some(path) = control_path {
+        let _ = std::fs::remove_file(path);
+    }
+
+    let signal = result?;
+
+    dht.shutdown().await;
+
+    write_routing_table(&mut std::io::stdout().lock(), &dht.routing_table().await, args.net.output)?;
+
+    match signal {
+        Some(signal) => Err(Interrupted(signal).into()),
+        None => Ok(()),
+    }
+}
+
+/// Every setting `crawl` and `serve` would run with, given `file`, as
+/// TOML.
+pub fn check_config(file: &ConfigFile) -> Result<String> {
+    let with_config = |name: &str| -> Result<Command> {
+        let matches = Cli::command().try_get_matches_from(["kademliar", name])?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+
+        if let Some((_, matches)) = matches.subcommand() {
+            cli.command.apply_config(file, matches);
+        }
+
+        Ok(cli.command)
+    };
+
+    let (Command::Crawl(crawl), Command::Serve(serve)) = (with_config("crawl")?, with_config("serve")?) else {
+        unreachable!("parsed crawl and serve");
+    };
+
+    crawl.net.validate()?;
+    serve.config()?;
+
+    Ok(toml::to_string(&ConfigFile::effective(&crawl, &serve))?)
+}
+
+pub async fn main() -> Result<()> {
+    let cli = Cli::parse_with_config(std::env::args_os())?;
+
+    cli.log.init()?;
+
+    debug!(?cli);
+
+    match cli.command {
+        Command::Ping { addr, net } => {
+            let dht = net.start_dht().await?;
+            let addrs = BootstrapNode::from_str(&addr)?.resolve().await?;
+            let (id, addr) = until_signal(dht.ping_any(&addrs)).await?;
+
+            write_result(&mut std::io::stdout().lock(), &PingJson::new(&id, &addr), net.output)
+        },
+
+        Command::FindNode { target, net } => {
+            let target = match target {
+                Some(value) => parse_hex_id("target", &value)?,
+                None => NodeId::random(ID_LEN_BYTES),
+            };
+
+            let dht = net.start_dht().await?;
+            let nodes = until_signal(dht.find_node(target.clone())).await?;
+
+            write_result(&mut std::io::stdout().lock(), &LookupJson::new(&target, &nodes), net.output)
+        },
+
+        Command::GetPeers { info_hash, net } => {
+            let info_hash = parse_hex_id("info hash", &info_hash)?;
+            let dht = net.start_dht().await?;
+            let result = until_signal(dht.get_peers(info_hash.clone())).await?;
+
+            write_result(
+                &mut std::io::stdout().lock(),
+                &GetPeersJson::new(&info_hash, &result),
+                net.output
+            )
+        },
+
+        Command::Announce { info_hash, port, net } => {
+            let info_hash = parse_hex_id("info hash", &info_hash)?;
+            let dht = net.start_dht().await?;
+            let nodes = until_signal(dht.announce_peer(info_hash.clone(), port)).await?;
+
+            write_result(
+                &mut std::io::stdout().lock(),
+                &AnnounceJson::new(&info_hash, port, &nodes),
+                net.output
+            )
+        },
+
+        Command::Crawl(args) => App::main(args).await,
+
+        Command::Serve(args) => serve(args).await,
+
+        Command::Ctl { socket, method, params } => {
+            let params = match params {
+                Some(value) => serde_json::from_str(&value).context("invalid params")?,
+                None => serde_json::Value::Null,
+            };
+
+            let result = call_remote(&socket, &method, params).await?;
+
+            println!("{}", serde_json::to_string_pretty(&result)?);
+            Ok(())
+        },
+
+        Command::Config(ConfigCommand::Check) => {
+            let file = match &cli.config {
+                Some(path) => ConfigFile::load(path)?,
+                None => ConfigFile::default(),
+            };
+
+            print!("{}", check_config(&file)?);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands() -> Result<()> {
+        let _env = lock_env();
+        let cli = Cli::try_parse_from(["kademliar", "ping", "1.2.3.4:6881", "--output", "json"])?;
+
+        assert!(matches!(
+            cli.command,
+            Command::Ping { ref addr, ref net } if addr == "1.2.3.4:6881" && net.output == OutputFormat::Json
+        ));
+
+        let cli = Cli::try_parse_from(["kademliar", "crawl", "-b", "127.0.0.1:1000", "--bind", "127.0.0.1:0"])?;
+
+        let Command::Crawl(args) = cli.command else {
+            panic!("not crawl: {:?}", cli.command);
+        };
+
+        assert_eq!(args.net.bootstrap_node, vec!["127.0.0.1:1000"]);
+
+        assert!(Cli::try_parse_from(["kademliar", "get-peers", "abc"]).is_ok());
+        assert!(Cli::try_parse_from(["kademliar", "announce"]).is_err());
+        assert!(Cli::try_parse_from(["kademliar", "ctl", "-s", "node.sock", "ping", "{}"]).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_ids_of_invalid_length() {
+        assert!(parse_hex_id("target", "35a35935f5226f7a6adcb84aa4da1b62c71023e1").is_ok());
+        assert!(parse_hex_id("target", "35a3").is_err());
+        assert!(parse_hex_id("target", "not hex").is_err());
+    }
+}
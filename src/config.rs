@@ -0,0 +1,336 @@
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::app::*;
+use crate::cli::*;
+use crate::output::*;
+
+/// Settings from the TOML file given with `--config`. The keys are the long
+/// flags with underscores instead of dashes, e.g. `timeout_ms = 2000`, and
+/// all of them are optional.
+///
+/// A setting given as flag or as `KADEMLIAR_*` environment variable wins
+/// over the file, which wins over the default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub k: Option<usize>,
+    pub concurrency: Option<usize>,
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub max_packets_per_sec: Option<u32>,
+    pub max_bytes_per_sec: Option<u32>,
+    pub max_inbound_packets_per_sec: Option<u32>,
+    pub inbound_block_secs: Option<u64>,
+    pub max_inbound_sources: Option<usize>,
+    pub max_info_hashes: Option<usize>,
+    pub max_peers_per_info_hash: Option<usize>,
+    pub bootstrap_node: Option<Vec<String>>,
+    pub bind: Option<String>,
+    pub id: Option<String>,
+    pub blocklist: Option<PathBuf>,
+    pub output: Option<OutputFormat>,
+
+    // crawl
+    pub bootstrap_retries: Option<u32>,
+    pub nodes_file: Option<PathBuf>,
+    pub discovered: Option<PathBuf>,
+    pub discovered_format: Option<DiscoveryFormat>,
+
+    // serve
+    pub announce: Option<Vec<String>>,
+    pub port: Option<u16>,
+    pub refresh_secs: Option<u64>,
+    pub ping_secs: Option<u64>,
+    pub republish_secs: Option<u64>,
+    pub control_socket: Option<PathBuf>,
+    pub metrics: Option<String>,
+
+    #[cfg(feature = "http")]
+    pub http: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config: {:?}", path))?;
+
+        toml::from_str(&data).with_context(|| format!("invalid config: {:?}", path))
+    }
+
+    /// Sets everything in `args` that `matches` only has the default for.
+    pub fn apply_network(&self, matches: &ArgMatches, args: &mut NetworkArgs) {
+        let layer = Layer(matches);
+
+        layer.value("k", &mut args.k, &self.k);
+        layer.value("concurrency", &mut args.concurrency, &self.concurrency);
+        layer.value("timeout_ms", &mut args.timeout_ms, &self.timeout_ms);
+        layer.value("retries", &mut args.retries, &self.retries);
+        layer.value("retry_backoff_ms", &mut args.retry_backoff_ms, &self.retry_backoff_ms);
+        layer.option("max_packets_per_sec", &mut args.max_packets_per_sec, &self.max_packets_per_sec);
+        layer.option("max_bytes_per_sec", &mut args.max_bytes_per_sec, &self.max_bytes_per_sec);
+        layer.value("max_inbound_packets_per_sec", &mut args.max_inbound_packets_per_sec, &self.max_inbound_packets_per_sec);
+        layer.value("inbound_block_secs", &mut args.inbound_block_secs, &self.inbound_block_secs);
+        layer.value("max_inbound_sources", &mut args.max_inbound_sources, &self.max_inbound_sources);
+        layer.value("max_info_hashes", &mut args.max_info_hashes, &self.max_info_hashes);
+        layer.value("max_peers_per_info_hash", &mut args.max_peers_per_info_hash, &self.max_peers_per_info_hash);
+        layer.value("bootstrap_node", &mut args.bootstrap_node, &self.bootstrap_node);
+        layer.value("bind", &mut args.bind, &self.bind);
+        layer.option("id", &mut args.id, &self.id);
+        layer.option("blocklist", &mut args.blocklist, &self.blocklist);
+        layer.value("output", &mut args.output, &self.output);
+    }
+
+    pub fn apply_crawl(&self, matches: &ArgMatches, args: &mut AppArgs) {
+        let layer = Layer(matches);
+
+        self.apply_network(matches, &mut args.net);
+
+        layer.value("bootstrap_retries", &mut args.bootstrap_retries, &self.bootstrap_retries);
+        layer.option("nodes_file", &mut args.nodes_file, &self.nodes_file);
+        layer.option("discovered", &mut args.discovered, &self.discovered);
+        layer.value("discovered_format", &mut args.discovered_format, &self.discovered_format);
+    }
+
+    pub fn apply_serve(&self, matches: &ArgMatches, args: &mut ServeArgs) {
+        let layer = Layer(matches);
+
+        self.apply_network(matches, &mut args.net);
+
+        layer.value("announce", &mut args.announce, &self.announce);
+        layer.option("port", &mut args.port, &self.port);
+        layer.value("refresh_secs", &mut args.refresh_secs, &self.refresh_secs);
+        layer.value("ping_secs", &mut args.ping_secs, &self.ping_secs);
+        layer.value("republish_secs", &mut args.republish_secs, &self.republish_secs);
+        layer.option("control_socket", &mut args.control_socket, &self.control_socket);
+        layer.option("metrics", &mut args.metrics, &self.metrics);
+
+        #[cfg(feature = "http")]
+        layer.option("http", &mut args.http, &self.http);
+    }
+
+    /// Every setting `crawl` and `serve` run with. The network settings are
+    /// taken from `crawl`, they're the same for both.
+    pub fn effective(crawl: &AppArgs, serve: &ServeArgs) -> Self {
+        let net = &crawl.net;
+
+        Self {
+            k: Some(net.k),
+            concurrency: Some(net.concurrency),
+            timeout_ms: Some(net.timeout_ms),
+            retries: Some(net.retries),
+            retry_backoff_ms: Some(net.retry_backoff_ms),
+            max_packets_per_sec: net.max_packets_per_sec,
+            max_bytes_per_sec: net.max_bytes_per_sec,
+            max_inbound_packets_per_sec: Some(net.max_inbound_packets_per_sec),
+            inbound_block_secs: Some(net.inbound_block_secs),
+            max_inbound_sources: Some(net.max_inbound_sources),
+            max_info_hashes: Some(net.max_info_hashes),
+            max_peers_per_info_hash: Some(net.max_peers_per_info_hash),
+            bootstrap_node: Some(net.bootstrap_node.clone()),
+            bind: Some(net.bind.clone()),
+            id: net.id.clone(),
+            blocklist: net.blocklist.clone(),
+            output: Some(net.output),
+            bootstrap_retries: Some(crawl.bootstrap_retries),
+            nodes_file: crawl.nodes_file.clone(),
+            discovered: crawl.discovered.clone(),
+            discovered_format: Some(crawl.discovered_format),
+            announce: Some(serve.announce.clone()),
+            port: serve.port,
+            refresh_secs: Some(serve.refresh_secs),
+            ping_secs: Some(serve.ping_secs),
+            republish_secs: Some(serve.republish_secs),
+            control_socket: serve.control_socket.clone(),
+            metrics: serve.metrics.clone(),
+
+            #[cfg(feature = "http")]
+            http: serve.http.clone(),
+        }
+    }
+}
+
+/// Puts values from the file under the ones from flags and the environment.
+struct Layer<'a>(&'a ArgMatches);
+
+impl Layer<'_> {
+    /// Whether clap only has the default for `id`, if any.
+    fn is_default(&self, id: &str) -> bool {
+        !matches!(
+            self.0.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    }
+
+    fn value<T: Clone>(&self, id: &str, arg: &mut T, file: &Option<T>) {
+        if let Some(value) = file {
+            if self.is_default(id) {
+                *arg = value.clone();
+            }
+        }
+    }
+
+    fn option<T: Clone>(&self, id: &str, arg: &mut Option<T>, file: &Option<T>) {
+        if file.is_some() && self.is_default(id) {
+            *arg = file.clone();
+        }
+    }
+}
+
+/// Held by tests that set environment variables or parse flags, which fall
+/// back to them, so they don't see each other's variables.
+#[cfg(test)]
+pub(crate) fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Environment variable that is removed again once dropped, also when
+    /// the test fails.
+    struct EnvVar(&'static str);
+
+    impl EnvVar {
+        fn set(name: &'static str, value: &str) -> Self {
+            std::env::set_var(name, value);
+            Self(name)
+        }
+    }
+
+    impl Drop for EnvVar {
+        fn drop(&mut self) {
+            std::env::remove_var(self.0);
+        }
+    }
+
+    fn write_config(name: &str, data: &str) -> Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!("kademliar-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, data)?;
+
+        Ok(path)
+    }
+
+    #[test]
+    fn puts_flags_and_env_over_file_over_defaults() -> Result<()> {
+        let path = write_config("layers", r#"
+            k = 20
+            concurrency = 10
+            timeout_ms = 2000
+            bootstrap_node = ["127.0.0.1:1000"]
+            output = "json"
+            max_info_hashes = 5
+        "#)?;
+
+        let cli = {
+            let _env = lock_env();
+            let _timeout = EnvVar::set("KADEMLIAR_TIMEOUT_MS", "3000");
+
+            Cli::parse_with_config([
+                "kademliar", "--config", path.to_str().unwrap(), "crawl", "-j", "4",
+            ])
+        };
+
+        std::fs::remove_file(&path)?;
+
+        let Command::Crawl(args) = cli?.command else {
+            panic!("expected crawl");
+        };
+
+        assert_eq!(args.net.k, 20);
+        assert_eq!(args.net.concurrency, 4);
+        assert_eq!(args.net.timeout_ms, 3000);
+        assert_eq!(args.net.retries, 0);
+        assert_eq!(args.net.bootstrap_node, vec!["127.0.0.1:1000"]);
+        assert_eq!(args.net.output, OutputFormat::Json);
+        assert_eq!(args.net.max_info_hashes, 5);
+        assert_eq!(args.bootstrap_retries, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_keys() -> Result<()> {
+        let path = write_config("unknown", "timeout = 2000\n")?;
+        let err = ConfigFile::load(&path).unwrap_err();
+
+        std::fs::remove_file(&path)?;
+
+        assert!(format!("{:#}", err).contains("unknown field `timeout`"), "{:#}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_and_prints_effective_config() -> Result<()> {
+        let _env = lock_env();
+        let out = check_config(&ConfigFile { k: Some(20), ..Default::default() })?;
+
+        assert!(out.contains("k = 20\n"), "{}", out);
+        assert!(out.contains("refresh_secs = 60\n"), "{}", out);
+        assert_eq!(toml::from_str::<ConfigFile>(&out)?.k, Some(20));
+
+        assert!(check_config(&ConfigFile { id: Some("abc".into()), ..Default::default() }).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_zero_k_concurrency_and_timeout() {
+        let _env = lock_env();
+
+        for (file, name) in [
+            (ConfigFile { k: Some(0), ..Default::default() }, "k"),
+            (ConfigFile { concurrency: Some(0), ..Default::default() }, "concurrency"),
+            (ConfigFile { timeout_ms: Some(0), ..Default::default() }, "timeout_ms"),
+        ] {
+            let err = check_config(&file).unwrap_err();
+            assert_eq!(err.to_string(), format!("{} must be at least 1", name));
+        }
+    }
+
+    #[test]
+    fn rejects_zero_outbound_rate_limits() {
+        let _env = lock_env();
+
+        for (file, name) in [
+            (ConfigFile { max_packets_per_sec: Some(0), ..Default::default() }, "max_packets_per_sec"),
+            (ConfigFile { max_bytes_per_sec: Some(0), ..Default::default() }, "max_bytes_per_sec"),
+        ] {
+            let err = check_config(&file).unwrap_err();
+            assert_eq!(err.to_string(), format!("{} must be at least 1, or unset for no limit", name));
+        }
+    }
+
+    #[test]
+    fn rejects_zero_inbound_rate_limit() {
+        let _env = lock_env();
+
+        let file = ConfigFile { max_inbound_packets_per_sec: Some(0), ..Default::default() };
+        let err = check_config(&file).unwrap_err();
+
+        assert_eq!(err.to_string(), "max_inbound_packets_per_sec must be at least 1");
+    }
+
+    #[test]
+    fn rejects_zero_serve_periods() {
+        let _env = lock_env();
+
+        for (file, name) in [
+            (ConfigFile { refresh_secs: Some(0), ..Default::default() }, "refresh_secs"),
+            (ConfigFile { ping_secs: Some(0), ..Default::default() }, "ping_secs"),
+            (ConfigFile { republish_secs: Some(0), ..Default::default() }, "republish_secs"),
+        ] {
+            let err = check_config(&file).unwrap_err();
+            assert_eq!(err.to_string(), format!("{} must be at least 1", name));
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::bootstrap::*;
+use crate::cli::parse_hex_id;
+use crate::dht::*;
+use crate::krpc::*;
+use crate::output::*;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// The method was called correctly, but failed, e.g. a query timed out.
+pub const CALL_FAILED: i64 = -32000;
+
+/// JSON-RPC 2.0 request. One per line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+impl RpcRequest {
+    pub fn new(id: u64, method: impl Into<String>, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id: id.into(),
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+/// JSON-RPC 2.0 response. One per line. Has either a result or an error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        Self { jsonrpc: "2.0".into(), id, result, error }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error {}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FindNodeParams {
+    /// Random if not set.
+    target: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PingParams {
+    addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetPeersParams {
+    info_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnnounceParams {
+    info_hash: String,
+    port: Option<u16>,
+}
+
+/// Answers JSON-RPC requests against a running node on a Unix socket.
+///
+/// Methods: `routing_table`, `requests`, `find_node {target?}`,
+/// `ping {addr}`, `get_peers {info_hash}`, `announce {info_hash, port?}` and
+/// `shutdown`.
+#[derive(Debug)]
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    dht: Dht,
+    shutdown_tx: mpsc::Sender<()>,
+}
+
+impl ControlServer {
+    /// Listens on `path`, replacing a socket left behind by an earlier run,
+    /// but nothing else. Only the owner may connect. `shutdown_tx` gets a
+    /// message when a client asks the node to shut down.
+    pub fn bind(path: impl AsRef<Path>, dht: Dht, shutdown_tx: mpsc::Sender<()>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("refusing to replace {:?}, it's not a socket", path));
+            }
+
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to remove old socket: {:?}", path))?;
+        }
+
+        let listener = bind_private(&path)
+            .with_context(|| format!("failed to bind control socket: {:?}", path))?;
+
+        info!(?path, "control: listening");
+
+        Ok(Self { path, listener, dht, shutdown_tx })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    async fn run(self) {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Most likely out of file descriptors, which retrying
+                    // right away won't change.
+                    error!(err = ?e, "control: accept failed");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                },
+            };
+
+            let dht = self.dht.clone();
+            let shutdown_tx = self.shutdown_tx.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &dht, &shutdown_tx).await {
+                    debug!(err = ?e, "control: connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// Binds in a directory only we can enter, and only moves the socket to
+/// `path` once others can't connect to it, whatever the umask.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| anyhow!("not a file name"))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let result = (|| {
+        let tmp = dir.join("sock");
+        let listener = UnixListener::bind(&tmp)?;
+
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(listener)
+    })();
+
+    let _ = std::fs::remove_dir_all(&dir);
+
+    result
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    dht: &Dht,
+    shutdown_tx: &mpsc::Sender<()>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let res = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(req) => handle_request(dht, shutdown_tx, req).await,
+            Err(e) => RpcResponse::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+        };
+
+        let mut data = serde_json::to_vec(&res)?;
+        data.push(b'\n');
+
+        writer.write_all(&data).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn handle_request(
+    dht: &Dht,
+    shutdown_tx: &mpsc::Sender<()>,
+    req: RpcRequest,
+) -> RpcResponse {
+    debug!(?req, "control: request");
+
+    if req.jsonrpc != "2.0" {
+        return RpcResponse::new(req.id, Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be 2.0")));
+    }
+
+    let result = call(dht, shutdown_tx, &req.method, req.params).await;
+
+    RpcResponse::new(req.id, result)
+}
+
+async fn call(
+    dht: &Dht,
+    shutdown_tx: &mpsc::Sender<()>,
+    method: &str,
+    params: Value,
+) -> Result<Value, RpcError> {
+    match method {
+        "routing_table" => {
+            to_value(&RoutingTableJson::new(&dht.routing_table().await, SystemTime::now()))
+        },
+
+        "requests" => {
+            let requests: Vec<RequestJson> = dht
+                .in_flight()
+                .await
+                .iter()
+                .map(RequestJson::new)
+                .collect();
+
+            to_value(&requests)
+        },
+
+        "find_node" => {
+            let params: FindNodeParams = if params.is_null() {
+                FindNodeParams::default()
+            } else {
+                parse_params(params)?
+            };
+
+            let target = match params.target {
+                Some(value) => parse_hex_id("target", &value).map_err(invalid_params)?,
+                None => NodeId::random(ID_LEN_BYTES),
+            };
+
+            let nodes = dht.find_node(target.clone()).await.map_err(call_failed)?;
+
+            to_value(&LookupJson::new(&target, &nodes))
+        },
+
+        "ping" => {
+            let params: PingParams = parse_params(params)?;
+
+            let addrs = BootstrapNode::from_str(&params.addr)
+                .map_err(invalid_params)?
+                .resolve()
+                .await
+                .map_err(call_failed)?;
+
+            let (id, addr) = dht.ping_any(&addrs).await.map_err(call_failed)?;
+
+            to_value(&PingJson::new(&id, &addr))
+        },
+
+        "get_peers" => {
+            let params: GetPeersParams = parse_params(params)?;
+            let info_hash = parse_hex_id("info hash", &params.info_hash).map_err(invalid_params)?;
+            let result = dht.get_peers(info_hash.clone()).await.map_err(call_failed)?;
+
+            to_value(&GetPeersJson::new(&info_hash, &result))
+        },
+
+        "announce" => {
+            let params: AnnounceParams = parse_params(params)?;
+            let info_hash = parse_hex_id("info hash", &params.info_hash).map_err(invalid_params)?;
+
+            let nodes = dht
+                .announce_peer(info_hash.clone(), params.port)
+                .await
+                .map_err(call_failed)?;
+
+            to_value(&AnnounceJson::new(&info_hash, params.port, &nodes))
+        },
+
+        "shutdown" => {
+            info!("control: shutdown requested");
+
+            shutdown_tx.send(()).await.map_err(call_failed)?;
+
+            Ok(Value::Null)
+        },
+
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", method))),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(invalid_params)
+}
+
+fn to_value(value: &impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(CALL_FAILED, e))
+}
+
+fn invalid_params(e: impl ToString) -> RpcError {
+    RpcError::new(INVALID_PARAMS, e)
+}
+
+fn call_failed(e: impl ToString) -> RpcError {
+    RpcError::new(CALL_FAILED, e)
+}
+
+/// Sends a single request to the node listening on `path` and returns the
+/// result.
+pub async fn call_remote(path: impl AsRef<Path>, method: &str, params: Value) -> Result<Value> {
+    let path = path.as_ref();
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("failed to connect to control socket: {:?}", path))?;
+
+    let (reader, mut writer) = stream.into_split();
+
+    let mut data = serde_json::to_vec(&RpcRequest::new(1, method, params))?;
+    data.push(b'\n');
+
+    writer.write_all(&data).await?;
+
+    let Some(line) = BufReader::new(reader).lines().next_line().await? else {
+        return Err(anyhow!("connection closed without a response"));
+    };
+
+    let res: RpcResponse = serde_json::from_str(&line)?;
+
+    if let Some(e) = res.error {
+        return Err(anyhow!("{}", e));
+    }
+
+    Ok(res.result.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_requests_over_unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kademliar-control-{}.sock", std::process::id()));
+        let dht = Dht::builder(DhtConfig::default()).socket(KrpcSocketStub).build()?;
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        let server = ControlServer::bind(&path, dht.clone(), shutdown_tx)?.spawn();
+
+        let table = call_remote(&path, "routing_table", Value::Null).await?;
+        assert_eq!(table["id"], dht.id().to_string());
+
+        assert_eq!(call_remote(&path, "requests", Value::Null).await?, serde_json::json!([]));
+
+        // Never answered, as the stub socket doesn't receive anything.
+        tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        let requests = loop {
+            let requests = call_remote(&path, "requests", Value::Null).await?;
+
+            if requests != serde_json::json!([]) {
+                break requests;
+            }
+
+            tokio::task::yield_now().await;
+        };
+
+        assert_eq!(requests[0]["dst"], "127.0.0.1:1000");
+        assert_eq!(requests[0]["method"], "ping");
+
+        let err = call_remote(&path, "ping", serde_json::json!({})).await.unwrap_err();
+        assert!(err.to_string().contains(&INVALID_PARAMS.to_string()));
+
+        let err = call_remote(&path, "nope", Value::Null).await.unwrap_err();
+        assert!(err.to_string().contains(&METHOD_NOT_FOUND.to_string()));
+
+        call_remote(&path, "shutdown", Value::Null).await?;
+        assert_eq!(shutdown_rx.recv().await, Some(()));
+
+        server.abort();
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binds_for_owner_only_and_replaces_only_sockets() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kademliar-control-bind-{}.sock", std::process::id()));
+        let dht = Dht::builder(DhtConfig::default()).socket(KrpcSocketStub).build()?;
+        let (shutdown_tx, _shutdown_rx) = mpsc::channel(1);
+
+        std::fs::write(&path, "not a socket")?;
+
+        let err = ControlServer::bind(&path, dht.clone(), shutdown_tx.clone()).unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path)?, "not a socket");
+
+        std::fs::remove_file(&path)?;
+
+        // The second one replaces the socket the first one left behind.
+        for _ in 0..2 {
+            let server = ControlServer::bind(&path, dht.clone(), shutdown_tx.clone())?;
+            let metadata = std::fs::symlink_metadata(&path)?;
+
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+            drop(server);
+        }
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
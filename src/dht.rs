@@ -0,0 +1,1435 @@
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error, instrument, Instrument};
+
+use crate::blocklist::*;
+use crate::bootstrap::*;
+use crate::events::*;
+use crate::krpc::*;
+use crate::peers::*;
+use crate::ratelimit::*;
+use crate::routing::*;
+use crate::timeouts::*;
+
+#[derive(Debug, Clone, Error)]
+pub enum DhtError {
+    #[error("request timed out")]
+    Timeout,
+
+    #[error("failed to send request")]
+    SendFailed,
+
+    #[error("remote error {code}: {message}")]
+    Remote { code: i64, message: String },
+
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+
+    #[error("no nodes to query")]
+    NoNodes,
+
+    /// Every query of a lookup failed, e.g. because the bootstrap nodes are
+    /// unreachable.
+    #[error("no node responded")]
+    NoResponse,
+
+    #[error("node is blocked")]
+    Blocked,
+
+    #[error("dht has shut down")]
+    Shutdown,
+}
+
+/// Settings for a [`Dht`] node.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// How many nodes to keep per k-bucket.
+    pub k: usize,
+
+    /// How many requests to send at once.
+    pub concurrency: usize,
+
+    /// Timeout for requests, in milliseconds.
+    pub timeout_ms: u64,
+
+    /// ID to use, instead of randomly generating a new one.
+    pub id: Option<NodeId>,
+
+    /// How many times to retry a request that timed out, before giving up on
+    /// the node.
+    pub retries: u32,
+
+    /// Delay before the first retry, in milliseconds. Doubles with each
+    /// retry.
+    pub retry_backoff_ms: u64,
+
+    /// Nodes to query when the routing table is empty. Resolved again on
+    /// every bootstrap.
+    pub bootstrap_nodes: Vec<BootstrapNode>,
+
+    /// Cap on outgoing packets and bytes per second.
+    pub rate_limit: RateLimit,
+
+    /// Cap on incoming packets per second, per source.
+    pub inbound_limit: InboundLimit,
+
+    /// Cap on the peers other nodes announce to us.
+    pub peer_limit: PeerLimit,
+
+    /// Nodes that are never queried, stored or returned.
+    pub blocklist: Arc<Blocklist>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            k: 8,
+            concurrency: 3,
+            timeout_ms: 5_000,
+            retries: 0,
+            retry_backoff_ms: 500,
+            id: None,
+            bootstrap_nodes: vec![],
+            rate_limit: RateLimit::default(),
+            inbound_limit: InboundLimit::default(),
+            peer_limit: PeerLimit::default(),
+            blocklist: Arc::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DhtBuilder {
+    config: DhtConfig,
+    sock: Option<Arc<dyn KrpcSocket>>,
+}
+
+impl DhtBuilder {
+    pub fn new(config: DhtConfig) -> Self {
+        Self { config, sock: None }
+    }
+
+    pub fn socket(mut self, sock: impl KrpcSocket) -> Self {
+        self.sock = Some(Arc::new(sock));
+        self
+    }
+
+    /// Spawns the background tasks. Must be called from within a tokio
+    /// runtime.
+    pub fn build(self) -> anyhow::Result<Dht> {
+        let Some(sock) = self.sock else {
+            anyhow::bail!("socket missing");
+        };
+
+        // Nothing would ever be sent with a concurrency of 0, and lookups
+        // need room for at least one node.
+        for (name, value) in [
+            ("k", self.config.k as u64),
+            ("concurrency", self.config.concurrency as u64),
+            ("timeout_ms", self.config.timeout_ms),
+        ] {
+            if value == 0 {
+                anyhow::bail!("{} must be at least 1", name);
+            }
+        }
+
+        let id = match &self.config.id {
+            Some(id) if id.len() != ID_LEN_BYTES => anyhow::bail!(
+                "id is of invalid length (expected {}, got {})",
+                ID_LEN_BYTES, id.len()
+            ),
+
+            Some(id) => id.clone(),
+            None => NodeId::random(ID_LEN_BYTES),
+        };
+
+        let events = EventSender::new();
+        let stats = Arc::new(KrpcStats::default());
+        let routing = Arc::new(RwLock::new(
+            RoutingTable::new(id.clone(), self.config.k)
+                .with_events(events.clone())
+                .with_blocklist(self.config.blocklist.clone())
+        ));
+
+        let (cmd_tx, cmd_rx) = mpsc::channel::<DhtCommand>(1024);
+        let (sender_tx, sender_rx) = mpsc::channel::<KrpcMessage>(1024);
+        let (main_tx, main_rx) = mpsc::channel::<KrpcMessage>(1024);
+        let requests = Arc::new(RwLock::new(HashMap::new()));
+
+        let sender = KrpcSender {
+            requests: requests.clone(),
+            permits: Arc::new(Semaphore::new(self.config.concurrency)),
+            rate_limiter: RateLimiter::new(self.config.rate_limit),
+            stats: stats.clone(),
+            sock: sock.clone(),
+            sender_rx,
+            main_tx: main_tx.clone(),
+        };
+
+        let receiver = KrpcReceiver {
+            sock,
+            main_tx: main_tx.clone(),
+            events: events.clone(),
+            stats: stats.clone(),
+            rate_limiter: InboundRateLimiter::new(self.config.inbound_limit),
+            blocklist: self.config.blocklist.clone(),
+        };
+
+        let (timeouts, timeouts_task) = RequestTimeouts::spawn(main_tx.clone());
+
+        let engine = DhtEngine {
+            id: id.clone(),
+            k: self.config.k,
+            timeout_ms: self.config.timeout_ms,
+            retries: self.config.retries,
+            retry_backoff_ms: self.config.retry_backoff_ms,
+            requests: requests.clone(),
+            routing: routing.clone(),
+            pending: HashMap::new(),
+            tasks: vec![sender.spawn(), receiver.spawn()],
+            timeouts_task,
+            sender_tx,
+            timeouts,
+            peers: PeerStore::default().with_limit(self.config.peer_limit),
+            tokens: Tokens::default(),
+            events: events.clone(),
+            stats: stats.clone(),
+        };
+
+        tokio::spawn(engine.run(cmd_rx, main_rx));
+
+        Ok(Dht {
+            inner: Arc::new(DhtInner {
+                id,
+                config: self.config,
+                routing,
+                requests: requests.clone(),
+                cmd_tx,
+                events,
+                stats,
+            }),
+        })
+    }
+}
+
+/// Peers found for an info hash, along with the closest nodes that were
+/// asked for them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GetPeersResult {
+    pub peers: Vec<NodeAddr>,
+    pub nodes: Vec<Node>,
+}
+
+/// Handle to a running DHT node. Cheap to clone. The node keeps running in
+/// the background until the last handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Dht {
+    inner: Arc<DhtInner>,
+}
+
+#[derive(Debug)]
+struct DhtInner {
+    id: NodeId,
+    config: DhtConfig,
+    routing: Arc<RwLock<RoutingTable>>,
+    requests: Arc<RwLock<HashMap<TxId, KrpcRequest>>>,
+    cmd_tx: mpsc::Sender<DhtCommand>,
+    events: EventSender,
+    stats: Arc<KrpcStats>,
+}
+
+/// A query that was sent, or is about to be, and hasn't been answered yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlightRequest {
+    pub tx_id: TxId,
+    pub dst: NodeAddr,
+    pub method: &'static str,
+
+    /// How many times the query has been retried so far.
+    pub attempt: u32,
+}
+
+/// A node that answered a lookup query, along with its answer.
+struct LookupHit {
+    node: Node,
+    response: KrpcResponse,
+
+    /// Queries it took to learn about the node, 1 for nodes we knew before.
+    hops: usize,
+}
+
+impl Dht {
+    pub fn builder(config: DhtConfig) -> DhtBuilder {
+        DhtBuilder::new(config)
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.inner.id
+    }
+
+    /// Events from the routing table and the KRPC layer, from now on.
+    pub fn events(&self) -> impl Stream<Item = DhtEvent> + Send + 'static {
+        self.inner.events.subscribe()
+    }
+
+    /// The blocklist in use. Call [`Blocklist::reload`] on it to pick up
+    /// changes to its file.
+    pub fn blocklist(&self) -> &Arc<Blocklist> {
+        &self.inner.config.blocklist
+    }
+
+    pub fn stats(&self) -> KrpcStatsSnapshot {
+        self.inner.stats.snapshot()
+    }
+
+    /// The live counters and histograms behind [`Dht::stats`].
+    pub fn krpc_stats(&self) -> &KrpcStats {
+        &self.inner.stats
+    }
+
+    /// Snapshot of the routing table.
+    pub async fn routing_table(&self) -> RoutingTable {
+        self.inner.routing.read().await.clone()
+    }
+
+    /// Snapshot of the queries waiting for an answer.
+    pub async fn in_flight(&self) -> Vec<InFlightRequest> {
+        self.inner.requests
+            .read()
+            .await
+            .iter()
+            .map(|(tx_id, req)| InFlightRequest {
+                tx_id: tx_id.clone(),
+                dst: req.dst.clone(),
+                method: req.payload.method_name(),
+                attempt: req.attempt,
+            })
+            .collect()
+    }
+
+    /// Stops the node. Queries in flight, and any made from now on, fail
+    /// with [`DhtError::Shutdown`]. The routing table can still be read.
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+
+        if self.inner.cmd_tx.send(DhtCommand::Shutdown(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Looks up the nodes closest to our own id, filling the routing table.
+    /// Returns the number of nodes in the routing table afterwards.
+    pub async fn bootstrap(&self) -> Result<usize, DhtError> {
+        self.find_node(self.inner.id.clone()).await?;
+
+        Ok(self.inner.routing.read().await.len())
+    }
+
+    /// Pings a single node and returns its id.
+    pub async fn ping(&self, addr: NodeAddr) -> Result<NodeId, DhtError> {
+        let payload = KrpcQuery::Ping(PingRequest {
+            tx_id: TxId::default(),
+            node_id_self: self.inner.id.clone(),
+        });
+
+        self.query(addr, payload)
+            .await?
+            .node_id
+            .ok_or_else(|| DhtError::InvalidResponse("id missing".into()))
+    }
+
+    /// Pings `addrs` one after the other, e.g. every address a host name
+    /// resolved to, until one responds. Returns its id and address, or the
+    /// error of the last one.
+    pub async fn ping_any(&self, addrs: &[NodeAddr]) -> Result<(NodeId, NodeAddr), DhtError> {
+        let mut result = Err(DhtError::NoNodes);
+
+        for addr in addrs.iter() {
+            match self.ping(addr.clone()).await {
+                Ok(id) => return Ok((id, addr.clone())),
+
+                Err(e) => {
+                    debug!(?addr, err = ?e, "ping failed --> next address");
+                    result = Err(e);
+                },
+            }
+        }
+
+        result
+    }
+
+    /// Looks up the k closest nodes to `target` that responded.
+    pub async fn find_node(&self, target: NodeId) -> Result<Vec<Node>, DhtError> {
+        let hits = self.lookup(&target, |dht| {
+            KrpcQuery::FindNode(FindNodeRequest {
+                tx_id: TxId::default(),
+                node_id_self: dht.inner.id.clone(),
+                node_id_target: target.clone(),
+            })
+        }).await?;
+
+        Ok(hits.into_iter().map(|x| x.node).collect())
+    }
+
+    /// Asks the node at `addr` alone for the nodes closest to `target` it
+    /// knows of.
+    pub async fn find_node_at(&self, addr: NodeAddr, target: NodeId) -> Result<Vec<Node>, DhtError> {
+        let payload = KrpcQuery::FindNode(FindNodeRequest {
+            tx_id: TxId::default(),
+            node_id_self: self.inner.id.clone(),
+            node_id_target: target,
+        });
+
+        Ok(self.query(addr, payload).await?.nodes.unwrap_or_default())
+    }
+
+    /// Looks up peers for `info_hash`.
+    pub async fn get_peers(&self, info_hash: NodeId) -> Result<GetPeersResult, DhtError> {
+        let hits = self.get_peers_lookup(&info_hash).await?;
+        let mut result = GetPeersResult::default();
+
+        for hit in hits.into_iter() {
+            for peer in hit.response.values.unwrap_or_default() {
+                if !result.peers.contains(&peer) {
+                    result.peers.push(peer);
+                }
+            }
+
+            result.nodes.push(hit.node);
+        }
+
+        Ok(result)
+    }
+
+    /// Announces that we're a peer for `info_hash` to the closest nodes.
+    /// When `port` is `None`, the nodes use the source port of the request.
+    /// Returns the nodes that accepted the announcement.
+    pub async fn announce_peer(
+        &self,
+        info_hash: NodeId,
+        port: Option<u16>,
+    ) -> Result<Vec<Node>, DhtError> {
+        let hits = self.get_peers_lookup(&info_hash).await?;
+
+        let mut announces: FuturesUnordered<_> = hits
+            .into_iter()
+            .filter_map(|hit| {
+                let token = hit.response.token?;
+                let payload = KrpcQuery::AnnouncePeer(AnnouncePeerRequest {
+                    tx_id: TxId::default(),
+                    node_id_self: self.inner.id.clone(),
+                    info_hash: info_hash.clone(),
+                    port,
+                    token,
+                });
+
+                Some(async move {
+                    (hit.node.clone(), self.query(hit.node.addr, payload).await)
+                })
+            })
+            .collect();
+
+        let mut nodes = vec![];
+
+        while let Some((node, result)) = announces.next().await {
+            match result {
+                Ok(_) => nodes.push(node),
+                Err(e) => debug!(?node, err = ?e, "announce failed"),
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    async fn get_peers_lookup(&self, info_hash: &NodeId) -> Result<Vec<LookupHit>, DhtError> {
+        self.lookup(info_hash, |dht| {
+            KrpcQuery::GetPeers(GetPeersRequest {
+                tx_id: TxId::default(),
+                node_id_self: dht.inner.id.clone(),
+                info_hash: info_hash.clone(),
+            })
+        }).await
+    }
+
+    /// Iteratively queries the closest known nodes to `target`, until the k
+    /// closest nodes that responded have all been queried. Falls back to the
+    /// bootstrap nodes when the routing table is empty. Fails if none of the
+    /// nodes responded.
+    #[instrument(level = "debug", skip_all, fields(%target))]
+    async fn lookup<F>(&self, target: &NodeId, create_query: F) -> Result<Vec<LookupHit>, DhtError>
+    where
+        F: Fn(&Self) -> KrpcQuery,
+    {
+        let k = self.inner.config.k;
+        let started_at = Instant::now();
+
+        // Bootstrap nodes have no known id, so they sort first.
+        let mut candidates: Vec<(Option<Distance>, NodeAddr, usize)> = self
+            .inner
+            .routing
+            .read()
+            .await
+            .closest(target, k)
+            .into_iter()
+            .map(|x| (Some(x.id.distance_to(target)), x.addr, 1))
+            .collect();
+
+        if candidates.is_empty() {
+            candidates = resolve_all(&self.inner.config.bootstrap_nodes)
+                .await
+                .into_iter()
+                .map(|x| (None, x, 1))
+                .collect();
+        }
+
+        if candidates.is_empty() {
+            return Err(DhtError::NoNodes);
+        }
+
+        let mut seen: HashSet<NodeAddr> = candidates
+            .iter()
+            .map(|(_, addr, _)| addr.clone())
+            .collect();
+
+        let mut hits: Vec<LookupHit> = vec![];
+        let mut queried: usize = 0;
+        let mut in_flight = FuturesUnordered::new();
+
+        loop {
+            candidates.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+            while in_flight.len() < self.inner.config.concurrency {
+                let kth_closest = hits.get(k - 1).map(|x| x.node.id.distance_to(target));
+
+                let Some(position) = candidates.iter().position(|(distance, _, _)| {
+                    match (distance, &kth_closest) {
+                        (Some(distance), Some(kth_closest)) => distance < kth_closest,
+                        _ => true,
+                    }
+                }) else {
+                    break;
+                };
+
+                let (_, addr, hops) = candidates.remove(position);
+                let payload = create_query(self);
+
+                queried += 1;
+
+                in_flight.push(async move {
+                    let result = self.query(addr.clone(), payload).await;
+                    (addr, hops, result)
+                });
+            }
+
+            let Some((addr, hops, result)) = in_flight.next().await else {
+                break;
+            };
+
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!(?addr, err = ?e, "lookup: query failed");
+                    continue;
+                },
+            };
+
+            let Some(id) = response.node_id.clone() else {
+                continue;
+            };
+
+            let blocklist = &self.inner.config.blocklist;
+
+            if blocklist.blocks_id(&id) {
+                debug!(?addr, ?id, "lookup: node is blocked --> ignore");
+                continue;
+            }
+
+            for node in response.nodes.iter().flatten() {
+                if node.id != self.inner.id
+                    && !blocklist.blocks_node(node)
+                    && seen.insert(node.addr.clone())
+                {
+                    candidates.push((
+                        Some(node.id.distance_to(target)),
+                        node.addr.clone(),
+                        hops + 1,
+                    ));
+                }
+            }
+
+            hits.push(LookupHit {
+                node: Node { id, addr },
+                response,
+                hops,
+            });
+
+            hits.sort_by_cached_key(|x| x.node.id.distance_to(target));
+        }
+
+        self.inner.stats.lookup_duration.observe(started_at.elapsed());
+
+        self.inner.events.emit(DhtEvent::LookupFinished {
+            target: target.clone(),
+            queried,
+            responded: hits.len(),
+            hops: hits.first().map_or(0, |x| x.hops),
+            elapsed: started_at.elapsed(),
+        });
+
+        if hits.is_empty() {
+            return Err(DhtError::NoResponse);
+        }
+
+        hits.truncate(k);
+
+        Ok(hits)
+    }
+
+    /// Sends a single query. The engine assigns the transaction id, so the
+    /// one in `payload` is ignored.
+    async fn query(&self, dst: NodeAddr, payload: KrpcQuery) -> Result<KrpcResponse, DhtError> {
+        if self.inner.config.blocklist.blocks_ip(&dst.ip) {
+            return Err(DhtError::Blocked);
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.inner.cmd_tx
+            .send(DhtCommand::Query(KrpcRequest::new(dst, payload), reply_tx))
+            .await
+            .map_err(|_| DhtError::Shutdown)?;
+
+        reply_rx.await.map_err(|_| DhtError::Shutdown)?
+    }
+}
+
+type QueryReply = oneshot::Sender<Result<KrpcResponse, DhtError>>;
+
+#[derive(Debug)]
+enum DhtCommand {
+    Query(KrpcRequest, QueryReply),
+
+    /// Answered once the engine has stopped.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Background task that owns the in-flight requests and routes responses
+/// back to the handle that sent the query. Also answers queries from other
+/// nodes.
+struct DhtEngine {
+    id: NodeId,
+    k: usize,
+    timeout_ms: u64,
+    retries: u32,
+    retry_backoff_ms: u64,
+    requests: Arc<RwLock<HashMap<TxId, KrpcRequest>>>,
+    routing: Arc<RwLock<RoutingTable>>,
+    pending: HashMap<TxId, QueryReply>,
+    tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+    timeouts_task: JoinHandle<()>,
+    sender_tx: mpsc::Sender<KrpcMessage>,
+    timeouts: RequestTimeouts,
+    peers: PeerStore,
+    tokens: Tokens,
+    events: EventSender,
+    stats: Arc<KrpcStats>,
+}
+
+impl DhtEngine {
+    async fn run(
+        mut self,
+        mut cmd_rx: mpsc::Receiver<DhtCommand>,
+        mut main_rx: mpsc::Receiver<KrpcMessage>,
+    ) {
+        let done = loop {
+            tokio::select! {
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(DhtCommand::Query(req, reply)) => self.send_query(req, reply).await,
+                    Some(DhtCommand::Shutdown(done)) => break Some(done),
+                    None => break None, // every handle was dropped
+                },
+
+                Some(msg) = main_rx.recv() => match msg {
+                    KrpcMessage::Exit(_) => break None,
+
+                    msg => {
+                        let span = KrpcRequest::span_of(&*self.requests.read().await, &msg);
+                        self.handle_message(msg).instrument(span).await
+                    },
+                },
+            }
+        };
+
+        let cancelled = self.requests.write().await.drain().count();
+
+        for (_, reply) in self.pending.drain() {
+            let _ = reply.send(Err(DhtError::Shutdown));
+        }
+
+        debug!(cancelled, "dht: exit");
+
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+
+        self.timeouts_task.abort();
+
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+
+    async fn send_query(&mut self, req: KrpcRequest, reply: QueryReply) {
+        let tx_id = req.insert_into(&mut *self.requests.write().await);
+
+        self.pending.insert(tx_id.clone(), reply);
+
+        if self.sender_tx.send(KrpcMessage::Request(tx_id.clone())).await.is_err() {
+            self.finish(&tx_id, Err(DhtError::Shutdown)).await;
+        }
+    }
+
+    async fn handle_message(&mut self, msg: KrpcMessage) {
+        debug!(?msg, "dht: recv");
+
+        match msg {
+            KrpcMessage::Response(src, res) => {
+                let tx_id = res.tx_id.clone();
+
+                if !self.was_sent_to(&tx_id, &src).await {
+                    debug!(?src, ?tx_id, "response from unexpected source --> ignore");
+                    return;
+                }
+
+                if let Some(req) = self.finish(&tx_id, Ok(res.clone())).await {
+                    if let Some(sent_at) = req.sent_at {
+                        self.stats.rtt.observe(sent_at.elapsed());
+                    }
+
+                    if let Some(id) = res.node_id {
+                        let src = Node { id: id.clone(), addr: req.dst };
+
+                        {
+                            let mut routing = self.routing.write().await;
+
+                            routing.insert(src.clone());
+                            routing.mark_seen(&id, SystemTime::now());
+                        }
+
+                        if let Some(nodes) = res.nodes.filter(|x| !x.is_empty()) {
+                            self.events.emit(DhtEvent::NodesReceived { src, nodes });
+                        }
+                    }
+                }
+            },
+
+            KrpcMessage::Error(src, err) => {
+                let tx_id = err.tx_id.clone();
+
+                if !self.was_sent_to(&tx_id, &src).await {
+                    debug!(?src, ?tx_id, "error from unexpected source --> ignore");
+                    return;
+                }
+
+                self.finish(&tx_id, Err(DhtError::Remote {
+                    code: err.code,
+                    message: err.message,
+                })).await;
+            },
+
+            KrpcMessage::Request(tx_id) => {
+                let _ = self.sender_tx.send(KrpcMessage::Request(tx_id)).await;
+            },
+
+            KrpcMessage::Query(src, query) => {
+                let reply = self.answer(&src, query).await;
+                let _ = self.sender_tx.send(KrpcMessage::Reply(src, reply)).await;
+            },
+
+            KrpcMessage::SendSuccess(tx_id) => {
+                if let Some(req) = self.requests.write().await.get_mut(&tx_id) {
+                    req.sent_at = Some(Instant::now());
+                }
+
+                self.timeouts.start(tx_id, Duration::from_millis(self.timeout_ms));
+            },
+
+            KrpcMessage::SendError(tx_id) => {
+                let Some(req) = self.finish(&tx_id, Err(DhtError::SendFailed)).await else {
+                    return;
+                };
+
+                error!(?tx_id, dst = ?req.dst, "send error --> remove");
+            },
+
+            KrpcMessage::ResponseTimeout(tx_id) => {
+                let Some(req) = self.requests.write().await.remove(&tx_id) else {
+                    return;
+                };
+
+                let reply = self.pending.remove(&tx_id);
+
+                KrpcStats::add(&self.stats.timeouts);
+
+                self.events.emit(DhtEvent::QueryTimedOut {
+                    tx_id: tx_id.clone(),
+                    dst: req.dst.clone(),
+                    method: req.payload.method_name(),
+                });
+
+                if req.attempt < self.retries {
+                    let delay = retry_backoff(
+                        Duration::from_millis(self.retry_backoff_ms),
+                        req.attempt
+                    );
+
+                    let retry_tx_id = req
+                        .retry()
+                        .insert_into(&mut *self.requests.write().await);
+
+                    debug!(?tx_id, ?retry_tx_id, ?delay, "timeout --> retry");
+                    KrpcStats::add(&self.stats.retries);
+
+                    if let Some(reply) = reply {
+                        self.pending.insert(retry_tx_id.clone(), reply);
+                    }
+
+                    self.timeouts.delay_request(retry_tx_id, delay);
+                    return;
+                }
+
+                debug!(?tx_id, "timeout --> remove");
+
+                if let Some(reply) = reply {
+                    let _ = reply.send(Err(DhtError::Timeout));
+                }
+
+                // The query failed once all its retries did. The node is only
+                // evicted once it's bad, so a single failed query doesn't cost
+                // it its place.
+                let mut routing = self.routing.write().await;
+                let now = SystemTime::now();
+
+                for id in routing.ids_at(&req.dst).iter() {
+                    routing.mark_failed(id);
+
+                    if routing.liveness(id).status(now) == NodeStatus::Bad {
+                        routing.evict(id);
+                    }
+                }
+            },
+
+            _ => {},
+        }
+    }
+
+    /// Answers a query from `src`. The querying node is added to the routing
+    /// table, but only counts as seen once it answers one of our queries.
+    async fn answer(&mut self, src: &NodeAddr, query: KrpcIncomingQuery) -> KrpcReply {
+        let tx_id = query.tx_id.clone();
+
+        let protocol_error = |message: &str| {
+            KrpcReply::Error(KrpcError::new(tx_id.clone(), KrpcError::PROTOCOL_ERROR, message))
+        };
+
+        let Some(node_id) = query.node_id else {
+            return protocol_error("id missing");
+        };
+
+        self.routing.write().await.insert(Node { id: node_id.clone(), addr: src.clone() });
+
+        let mut res = KrpcResponse {
+            tx_id: tx_id.clone(),
+            node_id: Some(self.id.clone()),
+            ..Default::default()
+        };
+
+        match query.method_name.as_str() {
+            "ping" => {},
+
+            "find_node" => {
+                let Some(target) = query.target else {
+                    return protocol_error("target missing");
+                };
+
+                res.nodes = Some(self.closest_for(&node_id, &target).await);
+            },
+
+            "get_peers" => {
+                let Some(info_hash) = query.info_hash else {
+                    return protocol_error("info_hash missing");
+                };
+
+                let peers = self.peers.peers(&info_hash);
+
+                if peers.is_empty() {
+                    res.nodes = Some(self.closest_for(&node_id, &info_hash).await);
+                } else {
+                    res.values = Some(peers);
+                }
+
+                res.token = Some(self.tokens.issue(&src.ip));
+            },
+
+            "announce_peer" => {
+                let Some(info_hash) = query.info_hash else {
+                    return protocol_error("info_hash missing");
+                };
+
+                let valid_token = query
+                    .token
+                    .is_some_and(|x| self.tokens.verify(&src.ip, &x));
+
+                if !valid_token {
+                    return protocol_error("bad token");
+                }
+
+                let port = match (query.implied_port, query.port) {
+                    (true, _) => src.port,
+                    (false, Some(port)) => port,
+                    (false, None) => return protocol_error("port missing"),
+                };
+
+                self.peers.announce(info_hash, NodeAddr::new(src.ip, port));
+            },
+
+            method => {
+                return KrpcReply::Error(KrpcError::new(
+                    tx_id,
+                    KrpcError::METHOD_UNKNOWN,
+                    format!("method unknown: {}", method),
+                ));
+            },
+        }
+
+        KrpcReply::Response(res)
+    }
+
+    /// The k closest nodes to `target`, other than `requester`, which has no
+    /// use for itself.
+    async fn closest_for(&self, requester: &NodeId, target: &NodeId) -> Vec<Node> {
+        let mut nodes = self.routing.read().await.closest(target, self.k + 1);
+
+        nodes.retain(|x| x.id != *requester);
+        nodes.truncate(self.k);
+
+        nodes
+    }
+
+    /// Removes the request and hands the result to whoever sent the query.
+    async fn finish(
+        &mut self,
+        tx_id: &TxId,
+        result: Result<KrpcResponse, DhtError>,
+    ) -> Option<KrpcRequest> {
+        let req = self.requests.write().await.remove(tx_id)?;
+
+        self.timeouts.cancel(tx_id);
+
+        if let Some(reply) = self.pending.remove(tx_id) {
+            let _ = reply.send(result);
+        }
+
+        Some(req)
+    }
+
+    /// Whether the request with `tx_id` was sent to `src`. Answers from any
+    /// other node are ignored, so they can't complete someone else's query.
+    async fn was_sent_to(&self, tx_id: &TxId, src: &NodeAddr) -> bool {
+        self.requests
+            .read()
+            .await
+            .get(tx_id)
+            .is_some_and(|x| x.dst == *src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use bendy::encoding::ToBencode;
+    use std::str::FromStr;
+    use tokio::sync::Mutex;
+
+    /// Socket that hands sent datagrams to the test and receives whatever the
+    /// test feeds it, from the given source.
+    #[derive(Debug)]
+    struct KrpcSocketChannel {
+        sent_tx: mpsc::UnboundedSender<(Vec<u8>, String)>,
+        recv_rx: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, &'static str)>>,
+    }
+
+    #[async_trait]
+    impl KrpcSocket for KrpcSocketChannel {
+        async fn recv_from(&self, buf: &mut [u8]) -> tokio::io::Result<(usize, core::net::SocketAddr)> {
+            let Some((data, src)) = self.recv_rx.lock().await.recv().await else {
+                return futures::future::pending().await;
+            };
+
+            buf[..data.len()].copy_from_slice(&data);
+
+            Ok((data.len(), src.parse().unwrap()))
+        }
+
+        async fn send_to(&self, buf: &[u8], target: String) -> tokio::io::Result<usize> {
+            let _ = self.sent_tx.send((buf.to_vec(), target));
+            Ok(buf.len())
+        }
+    }
+
+    struct SetupResult {
+        dht: Dht,
+        sent_rx: mpsc::UnboundedReceiver<(Vec<u8>, String)>,
+        recv_tx: mpsc::UnboundedSender<(Vec<u8>, &'static str)>,
+    }
+
+    fn sent_tx_id(data: &[u8]) -> TxId {
+        let Ok(KrpcInbound::Query(query)) = KrpcInbound::from_bencode(data) else {
+            panic!("not a query: {:?}", data);
+        };
+
+        query.tx_id
+    }
+
+    fn with_tx_id(prefix: &[u8], tx_id: &TxId, suffix: &[u8]) -> Vec<u8> {
+        let mut data = prefix.to_vec();
+
+        data.extend_from_slice(format!("1:t{}:", tx_id.as_slice().len()).as_bytes());
+        data.extend_from_slice(tx_id.as_slice());
+        data.extend_from_slice(suffix);
+        data
+    }
+
+    fn ping_response(tx_id: &TxId) -> Vec<u8> {
+        with_tx_id(b"d1:rd2:id20:Viefohchaog3shoh7quie", tx_id, b"1:y1:re")
+    }
+
+    fn setup(config: DhtConfig) -> SetupResult {
+        let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+        let (recv_tx, recv_rx) = mpsc::unbounded_channel();
+
+        let dht = Dht::builder(config)
+            .socket(KrpcSocketChannel {
+                sent_tx,
+                recv_rx: Mutex::new(recv_rx),
+            })
+            .build()
+            .unwrap();
+
+        SetupResult { dht, sent_rx, recv_tx }
+    }
+
+    #[tokio::test]
+    async fn pings_node_and_adds_it_to_routing_table() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig::default());
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000")?).await.map_err(anyhow::Error::from) }
+        });
+
+        let (data, target) = sent_rx.recv().await.unwrap();
+        let tx_id = sent_tx_id(&data);
+
+        assert_eq!(target, "127.0.0.1:1000");
+        assert!(data.ends_with(&with_tx_id(b"1:q4:ping", &tx_id, b"1:y1:qe")));
+
+        recv_tx.send((ping_response(&tx_id), "127.0.0.1:1000"))?;
+
+        assert_eq!(task.await??, NodeId::from_str("Viefohchaog3shoh7qui")?);
+        assert_eq!(dht.routing_table().await.len(), 1);
+        assert_eq!(dht.krpc_stats().queries_sent_by_method.get("ping"), 1);
+        assert_eq!(dht.krpc_stats().rtt.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_addresses_in_turn_until_one_responds() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig {
+            timeout_ms: 1_000,
+            ..Default::default()
+        });
+
+        let addrs = vec![
+            NodeAddr::from_str("127.0.0.1:1000")?,
+            NodeAddr::from_str("127.0.0.1:1001")?,
+        ];
+
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            let addrs = addrs.clone();
+            async move { dht.ping_any(&addrs).await }
+        });
+
+        // The first one never answers.
+        let (_, target) = sent_rx.recv().await.unwrap();
+        assert_eq!(target, "127.0.0.1:1000");
+
+        let (data, target) = sent_rx.recv().await.unwrap();
+        assert_eq!(target, "127.0.0.1:1001");
+
+        recv_tx.send((ping_response(&sent_tx_id(&data)), "127.0.0.1:1001"))?;
+
+        let (id, addr) = task.await??;
+
+        assert_eq!(id, NodeId::from_str("Viefohchaog3shoh7qui")?);
+        assert_eq!(addr, addrs[1]);
+
+        assert!(matches!(dht.ping_any(&addrs[..1]).await, Err(DhtError::Timeout)));
+        assert!(matches!(dht.ping_any(&[]).await, Err(DhtError::NoNodes)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_queries_and_stores_announced_peers() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig::default());
+        let src = "127.0.0.1:1000";
+        let node_id_self = NodeId::from_str("Viefohchaog3shoh7qui")?;
+        let info_hash = NodeId::random(ID_LEN_BYTES);
+
+        let mut query = async |payload: KrpcQuery| {
+            recv_tx.send((payload.to_bencode().unwrap(), src)).unwrap();
+
+            let (data, target) = sent_rx.recv().await.unwrap();
+            assert_eq!(target, src);
+            KrpcInbound::from_bencode(&data).unwrap()
+        };
+
+        let get_peers = KrpcQuery::GetPeers(GetPeersRequest {
+            tx_id: TxId::from_u16(1),
+            node_id_self: node_id_self.clone(),
+            info_hash: info_hash.clone(),
+        });
+
+        let KrpcInbound::Response(res) = query(get_peers.clone()).await else {
+            panic!("not a response");
+        };
+
+        assert_eq!(res.tx_id, TxId::from_u16(1));
+        assert_eq!(res.node_id.as_ref(), Some(dht.id()));
+        assert_eq!(res.values, None);
+
+        // The querying node got added, but isn't returned to itself.
+        assert_eq!(res.nodes.map(|x| x.len()), Some(0));
+        assert_eq!(dht.routing_table().await.len(), 1);
+
+        let announce = |token: Vec<u8>| KrpcQuery::AnnouncePeer(AnnouncePeerRequest {
+            tx_id: TxId::from_u16(2),
+            node_id_self: node_id_self.clone(),
+            info_hash: info_hash.clone(),
+            port: Some(7000),
+            token,
+        });
+
+        let KrpcInbound::Error(err) = query(announce(b"bad".to_vec())).await else {
+            panic!("not an error");
+        };
+
+        assert_eq!(err.code, KrpcError::PROTOCOL_ERROR);
+
+        let KrpcInbound::Response(_) = query(announce(res.token.unwrap())).await else {
+            panic!("not a response");
+        };
+
+        let KrpcInbound::Response(res) = query(get_peers).await else {
+            panic!("not a response");
+        };
+
+        assert_eq!(res.values, Some(vec![NodeAddr::from_str("127.0.0.1:7000")?]));
+        assert_eq!(dht.stats().replies_sent, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn emits_events_for_routing_table_changes() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig::default());
+        let mut events = Box::pin(dht.events());
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        let (data, _) = sent_rx.recv().await.unwrap();
+        recv_tx.send((ping_response(&sent_tx_id(&data)), "127.0.0.1:1000"))?;
+        task.await??;
+
+        assert_eq!(
+            events.next().await,
+            Some(DhtEvent::NodeAdded {
+                node: Node {
+                    id: NodeId::from_str("Viefohchaog3shoh7qui")?,
+                    addr: NodeAddr::from_str("127.0.0.1:1000")?,
+                },
+                bucket: 0,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asks_single_node_and_emits_nodes_it_answered_with() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig::default());
+        let mut events = Box::pin(dht.events());
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.find_node_at(NodeAddr::from_str("127.0.0.1:1000").unwrap(), dht.id().clone()).await }
+        });
+
+        let (data, target) = sent_rx.recv().await.unwrap();
+        let tx_id = sent_tx_id(&data);
+
+        assert_eq!(target, "127.0.0.1:1000");
+
+        recv_tx.send((
+            with_tx_id(
+                b"d1:rd2:id20:Viefohchaog3shoh7qui5:nodes26:Aiquoh3iesh9thaiphe4\x7f\x00\x00\x02\x07\xd0e",
+                &tx_id,
+                b"1:y1:re",
+            ),
+            "127.0.0.1:1000",
+        ))?;
+
+        let node = Node {
+            id: NodeId::from_str("Aiquoh3iesh9thaiphe4")?,
+            addr: NodeAddr::from_str("127.0.0.2:2000")?,
+        };
+
+        assert_eq!(task.await??, vec![node.clone()]);
+
+        let src = Node {
+            id: NodeId::from_str("Viefohchaog3shoh7qui")?,
+            addr: NodeAddr::from_str("127.0.0.1:1000")?,
+        };
+
+        assert_eq!(events.next().await, Some(DhtEvent::NodeAdded { node: src.clone(), bucket: 0 }));
+        assert_eq!(events.next().await, Some(DhtEvent::NodesReceived { src, nodes: vec![node] }));
+
+        // Only nodes that answered make it into the routing table.
+        assert_eq!(dht.routing_table().await.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_timed_out_query_with_fresh_tx_id() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig {
+            timeout_ms: 1_000,
+            retries: 1,
+            ..Default::default()
+        });
+
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        let (first, _) = sent_rx.recv().await.unwrap();
+        let (second, _) = sent_rx.recv().await.unwrap();
+
+        let retry_tx_id = sent_tx_id(&second);
+
+        assert_ne!(sent_tx_id(&first), retry_tx_id);
+
+        recv_tx.send((ping_response(&retry_tx_id), "127.0.0.1:1000"))?;
+
+        assert!(task.await?.is_ok());
+        assert_eq!(dht.stats().retries, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_node_only_once_it_is_bad() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig {
+            timeout_ms: 1_000,
+            ..Default::default()
+        });
+
+        let addr = NodeAddr::from_str("127.0.0.1:1000")?;
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            let addr = addr.clone();
+            async move { dht.ping(addr).await }
+        });
+
+        let (data, _) = sent_rx.recv().await.unwrap();
+        recv_tx.send((ping_response(&sent_tx_id(&data)), "127.0.0.1:1000"))?;
+        task.await??;
+
+        for failures in 1..=NODE_BAD_AFTER_FAILURES {
+            assert!(matches!(dht.ping(addr.clone()).await, Err(DhtError::Timeout)));
+
+            let table = dht.routing_table().await;
+            let is_bad = failures == NODE_BAD_AFTER_FAILURES;
+
+            assert_eq!(table.is_empty(), is_bad, "after {} failures", failures);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_query_as_failed_once_its_retries_are_exhausted() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig {
+            timeout_ms: 1_000,
+            retries: 2,
+            ..Default::default()
+        });
+
+        let addr = NodeAddr::from_str("127.0.0.1:1000")?;
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            let addr = addr.clone();
+            async move { dht.ping(addr).await }
+        });
+
+        let (data, _) = sent_rx.recv().await.unwrap();
+        recv_tx.send((ping_response(&sent_tx_id(&data)), "127.0.0.1:1000"))?;
+        let id = task.await??;
+
+        assert!(matches!(dht.ping(addr).await, Err(DhtError::Timeout)));
+        assert_eq!(dht.stats().retries, 2);
+
+        // It answered just now, so it'd only go from good to questionable,
+        // never to bad, once it's been quiet for long enough.
+        let liveness = dht.routing_table().await.liveness(&id);
+        let later = SystemTime::now() + NODE_GOOD_FOR;
+
+        assert_eq!(liveness.failed_queries, 1);
+        assert_eq!(liveness.status(later), NodeStatus::Questionable);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn returns_remote_errors() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig::default());
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        let (data, _) = sent_rx.recv().await.unwrap();
+        recv_tx.send((
+            with_tx_id(b"d1:eli201e13:Generic Errore", &sent_tx_id(&data), b"1:y1:ee"),
+            "127.0.0.1:1000",
+        ))?;
+
+        assert!(matches!(
+            task.await?,
+            Err(DhtError::Remote { code: 201, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_responses_from_other_nodes() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, recv_tx } = setup(DhtConfig {
+            retries: 0,
+            ..Default::default()
+        });
+
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        let (data, _) = sent_rx.recv().await.unwrap();
+        recv_tx.send((ping_response(&sent_tx_id(&data)), "127.0.0.1:1001"))?;
+
+        assert!(matches!(task.await?, Err(DhtError::Timeout)));
+        assert!(dht.routing_table().await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn never_queries_blocked_nodes() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, .. } = setup(DhtConfig {
+            blocklist: Arc::new(Blocklist::new("127.0.0.0/8".parse()?)),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            dht.ping(NodeAddr::from_str("127.0.0.1:1000")?).await,
+            Err(DhtError::Blocked)
+        ));
+
+        assert!(sent_rx.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_to_build_with_zero_k_concurrency_or_timeout() {
+        for (config, name) in [
+            (DhtConfig { k: 0, ..Default::default() }, "k"),
+            (DhtConfig { concurrency: 0, ..Default::default() }, "concurrency"),
+            (DhtConfig { timeout_ms: 0, ..Default::default() }, "timeout_ms"),
+        ] {
+            let err = Dht::builder(config).socket(KrpcSocketStub).build().unwrap_err();
+            assert_eq!(err.to_string(), format!("{} must be at least 1", name));
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_lookup_without_nodes() {
+        let s = setup(DhtConfig::default());
+
+        assert!(matches!(
+            s.dht.find_node(NodeId::random(ID_LEN_BYTES)).await,
+            Err(DhtError::NoNodes)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_lookup_when_no_node_responds() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, .. } = setup(DhtConfig {
+            timeout_ms: 1_000,
+            bootstrap_nodes: vec![BootstrapNode::from_str("127.0.0.1:1000")?],
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            dht.find_node(NodeId::random(ID_LEN_BYTES)).await,
+            Err(DhtError::NoResponse)
+        ));
+
+        assert!(sent_rx.try_recv().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_queries_in_flight_on_shutdown() -> anyhow::Result<()> {
+        let SetupResult { dht, mut sent_rx, .. } = setup(DhtConfig::default());
+        let task = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(NodeAddr::from_str("127.0.0.1:1000").unwrap()).await }
+        });
+
+        sent_rx.recv().await.unwrap();
+        dht.shutdown().await;
+
+        assert!(matches!(task.await?, Err(DhtError::Shutdown)));
+        assert!(dht.in_flight().await.is_empty());
+
+        assert!(matches!(
+            dht.ping(NodeAddr::from_str("127.0.0.1:1000")?).await,
+            Err(DhtError::Shutdown)
+        ));
+
+        Ok(())
+    }
+}
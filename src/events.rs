@@ -0,0 +1,87 @@
+use futures::stream::Stream;
+use std::net::IpAddr;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
+use tracing::debug;
+
+use crate::krpc::*;
+
+const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DhtEvent {
+    NodeAdded { node: Node, bucket: usize },
+    NodeRemoved { node: Node, bucket: usize },
+
+    /// Removed because it stopped responding.
+    NodeEvicted { node: Node, bucket: usize },
+
+    /// The last bucket was split. `bucket` is the index of the new bucket.
+    BucketSplit { bucket: usize },
+
+    QueryReceived {
+        src: core::net::SocketAddr,
+        method: String,
+        node_id: Option<NodeId>,
+    },
+
+    /// Packets from `ip` are dropped for `duration`, because it kept going
+    /// over its rate limit.
+    SourceBlocked { ip: IpAddr, duration: Duration },
+
+    QueryTimedOut { tx_id: TxId, dst: NodeAddr, method: &'static str },
+
+    /// `src` answered one of our queries with `nodes`.
+    NodesReceived { src: Node, nodes: Vec<Node> },
+
+    /// `hops` is how many queries it took to learn about the closest node
+    /// that responded.
+    LookupFinished {
+        target: NodeId,
+        queried: usize,
+        responded: usize,
+        hops: usize,
+        elapsed: Duration,
+    },
+}
+
+/// Broadcasts events to every subscriber. Emitting is a no-op when there are
+/// none.
+#[derive(Debug, Clone)]
+pub struct EventSender(broadcast::Sender<DhtEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventSender {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self(tx)
+    }
+
+    pub fn emit(&self, event: DhtEvent) {
+        let _ = self.0.send(event);
+    }
+
+    /// Events emitted from now on. Subscribers that fall too far behind skip
+    /// the events they missed.
+    pub fn subscribe(&self) -> impl Stream<Item = DhtEvent> + Send + 'static {
+        futures::stream::unfold(self.0.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!(?skipped, "events: subscriber lagged");
+                    },
+
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::cli::parse_hex_id;
+use crate::dht::*;
+use crate::output::*;
+
+/// Error answered as `{"error": "<message>"}`.
+#[derive(Debug)]
+pub struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(e: impl ToString) -> Self {
+        Self(StatusCode::BAD_REQUEST, e.to_string())
+    }
+}
+
+impl From<DhtError> for ApiError {
+    fn from(e: DhtError) -> Self {
+        let status = match e {
+            DhtError::NoNodes => StatusCode::SERVICE_UNAVAILABLE,
+            DhtError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            DhtError::Blocked => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+
+        Self(status, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnounceBody {
+    pub info_hash: String,
+    pub port: Option<u16>,
+}
+
+/// Routes of the HTTP API. Responses use the same JSON documents as
+/// `--output json`.
+///
+/// - `GET /nodes`: the routing table
+/// - `GET /lookup/{target}`: the k closest nodes to `target`
+/// - `GET /peers/{info_hash}`: peers for `info_hash`
+/// - `POST /announce`: announces `{"info_hash": "<hex>", "port": 6881}`
+pub fn router(dht: Dht) -> Router {
+    Router::new()
+        .route("/nodes", get(nodes))
+        .route("/lookup/:target", get(lookup))
+        .route("/peers/:info_hash", get(peers))
+        .route("/announce", post(announce))
+        .with_state(dht)
+}
+
+/// Serves the API on `addr` in the background.
+pub async fn spawn(addr: &str, dht: Dht) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind http server: {}", addr))?;
+
+    info!(addr = ?listener.local_addr(), "http: listening");
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(dht)).await {
+            error!(err = ?e, "http: server failed");
+        }
+    }))
+}
+
+async fn nodes(State(dht): State<Dht>) -> Json<RoutingTableJson> {
+    Json(RoutingTableJson::new(&dht.routing_table().await, SystemTime::now()))
+}
+
+async fn lookup(
+    State(dht): State<Dht>,
+    Path(target): Path<String>,
+) -> Result<Json<LookupJson>, ApiError> {
+    let target = parse_hex_id("target", &target).map_err(ApiError::bad_request)?;
+    let nodes = dht.find_node(target.clone()).await?;
+
+    Ok(Json(LookupJson::new(&target, &nodes)))
+}
+
+async fn peers(
+    State(dht): State<Dht>,
+    Path(info_hash): Path<String>,
+) -> Result<Json<GetPeersJson>, ApiError> {
+    let info_hash = parse_hex_id("info hash", &info_hash).map_err(ApiError::bad_request)?;
+    let result = dht.get_peers(info_hash.clone()).await?;
+
+    Ok(Json(GetPeersJson::new(&info_hash, &result)))
+}
+
+async fn announce(
+    State(dht): State<Dht>,
+    Json(body): Json<AnnounceBody>,
+) -> Result<Json<AnnounceJson>, ApiError> {
+    let info_hash = parse_hex_id("info hash", &body.info_hash).map_err(ApiError::bad_request)?;
+    let nodes = dht.announce_peer(info_hash.clone(), body.port).await?;
+
+    Ok(Json(AnnounceJson::new(&info_hash, body.port, &nodes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krpc::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn request(addr: &str, request: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut response = String::new();
+
+        stream.write_all(request.as_bytes()).await?;
+        stream.read_to_string(&mut response).await?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn answers_with_json() -> Result<()> {
+        let dht = Dht::builder(DhtConfig::default()).socket(KrpcSocketStub).build()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+
+        tokio::spawn(async move { axum::serve(listener, router(dht)).await });
+
+        let res = request(&addr, "GET /nodes HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n").await?;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.contains("\"buckets\":[]"), "{}", res);
+
+        let res = request(&addr, "GET /lookup/abc HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n").await?;
+        assert!(res.starts_with("HTTP/1.1 400"), "{}", res);
+
+        // No nodes and no bootstrap nodes to ask.
+        let body = r#"{"info_hash": "35a35935f5226f7a6adcb84aa4da1b62c71023e1"}"#;
+        let res = request(&addr, &format!(
+            "POST /announce HTTP/1.1\r\nhost: x\r\nconnection: close\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(), body
+        )).await?;
+
+        assert!(res.starts_with("HTTP/1.1 503"), "{}", res);
+        assert!(res.contains("no nodes to query"), "{}", res);
+
+        Ok(())
+    }
+}
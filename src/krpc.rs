@@ -2,19 +2,30 @@ use async_trait::async_trait;
 use anyhow::{anyhow, bail, Context, Result};
 use bendy::{decoding::FromBencode, encoding::ToBencode};
 use rand::RngCore;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::marker::{Send, Sync};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{net::Ipv4Addr, str::FromStr};
-use tokio::sync::{mpsc, RwLock};
-use tokio::time::{sleep, Duration};
-use tracing::{debug, error};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, field, Instrument, Span};
+
+use crate::blocklist::*;
+use crate::events::*;
+use crate::metrics::*;
+use crate::ratelimit::*;
+use crate::shutdown::*;
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
 
 pub const ID_LEN_BYTES: usize = 20;
 pub const ID_LEN_BITS: usize = ID_LEN_BYTES * 8;
 pub const ID_LEN_HEX: usize = ID_LEN_BYTES * 2;
 
+/// Long enough that ids can't be guessed by trying a handful of them.
+pub const TX_ID_LEN_BYTES: usize = 4;
+
 const IPV4_LEN_BYTES: usize = 4;
 const PORT_LEN_BYTES: usize = 2;
 const NODE_LEN_BYTES: usize = ID_LEN_BYTES + IPV4_LEN_BYTES + PORT_LEN_BYTES;
@@ -28,7 +39,28 @@ impl TxId {
     }
 
     pub fn from_u16(src: u16) -> Self {
-        Self(src.to_ne_bytes().to_vec())
+        Self(src.to_be_bytes().to_vec())
+    }
+
+    pub fn random() -> Self {
+        let mut bytes = vec![0; TX_ID_LEN_BYTES];
+
+        rand
+            ::thread_rng()
+            .fill_bytes(&mut bytes);
+
+        Self(bytes)
+    }
+
+    /// Random id that isn't used by any of the in-flight `requests`.
+    pub fn unused_in<V>(requests: &HashMap<TxId, V>) -> Self {
+        loop {
+            let tx_id = Self::random();
+
+            if !requests.contains_key(&tx_id) {
+                return tx_id;
+            }
+        }
     }
 
     pub fn from_hex(src: &str) -> anyhow::Result<Self> {
@@ -52,16 +84,26 @@ impl FromStr for TxId {
     }
 }
 
+impl std::fmt::Display for TxId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
 impl std::fmt::Debug for TxId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "TxId({})", hex::encode(&self.0))
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct NodeId(Vec<u8>);
 
 impl NodeId {
+    pub fn new(src: impl Into<Vec<u8>>) -> Self {
+        Self(src.into())
+    }
+
     pub fn from_hex(src: &str) -> anyhow::Result<Self> {
         let bytes: Vec<u8> = hex::FromHex
             ::from_hex(src)
@@ -92,6 +134,32 @@ impl NodeId {
         self.0.is_empty()
     }
 
+    /// Random id whose distance to this one has a longest common prefix of
+    /// exactly `lcp` bits.
+    pub fn random_with_lcp(&self, lcp: usize) -> Self {
+        self.with_lcp(lcp, Self::random(self.len()))
+    }
+
+    /// `rest` with its first `lcp` bits taken from this id and the one after
+    /// flipped, so their distance has a longest common prefix of exactly
+    /// `lcp` bits.
+    pub fn with_lcp(&self, lcp: usize, rest: NodeId) -> Self {
+        assert_eq!(self.len(), rest.len());
+
+        let mut id = rest;
+
+        for bit in 0..=lcp.min(self.len() * 8 - 1) {
+            let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+            let own = self.0[byte] & mask;
+
+            // Same bits up to the prefix, then the first one that differs.
+            let value = if bit < lcp { own } else { own ^ mask };
+            id.0[byte] = (id.0[byte] & !mask) | value;
+        }
+
+        id
+    }
+
     pub fn distance_to(&self, other: &NodeId) -> Distance {
         assert_eq!(self.0.len(), other.0.len());
 
@@ -112,9 +180,9 @@ impl FromStr for NodeId {
     }
 }
 
-impl ToString for NodeId {
-    fn to_string(&self) -> String {
-        hex::encode(&self.0)
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
     }
 }
 
@@ -124,7 +192,7 @@ impl std::fmt::Debug for NodeId {
     }
 }
 
-#[derive(Clone, PartialEq, PartialOrd)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Distance(Vec<u8>);
 
 impl std::fmt::Debug for Distance {
@@ -136,6 +204,12 @@ impl std::fmt::Debug for Distance {
     }
 }
 
+impl std::fmt::Display for Distance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
 impl Distance {
     /// Longest common previx. Used to determine the k-bucket. Longer prefix
     /// means shorter distance.
@@ -156,13 +230,13 @@ impl Distance {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Node {
     pub id: NodeId,
     pub addr: NodeAddr,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeAddr {
     pub ip: Ipv4Addr,
     pub port: u16,
@@ -190,6 +264,23 @@ impl NodeAddr {
 
         Ok(Self { ip, port })
     }
+
+    pub fn to_compact_node_id(&self) -> Vec<u8> {
+        let mut value = self.ip.octets().to_vec();
+        value.extend_from_slice(&self.port.to_be_bytes());
+        value
+    }
+}
+
+impl TryFrom<core::net::SocketAddr> for NodeAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: core::net::SocketAddr) -> Result<Self, Self::Error> {
+        match value {
+            core::net::SocketAddr::V4(addr) => Ok(Self::new(*addr.ip(), addr.port())),
+            core::net::SocketAddr::V6(_) => Err(anyhow!("not ipv4: {}", value)),
+        }
+    }
 }
 
 impl FromStr for NodeAddr {
@@ -212,9 +303,59 @@ impl FromStr for NodeAddr {
     }
 }
 
-impl ToString for NodeAddr {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+impl std::fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+const KEY_ERROR: &[u8] = b"e";
+const KEY_MESSAGE_TYPE: &[u8] = b"y";
+const KEY_QUERY_ARGUMENTS: &[u8] = b"a";
+const KEY_QUERY_METHOD_NAME: &[u8] = b"q";
+const KEY_RETURN_VALUES: &[u8] = b"r";
+const KEY_TRANSACTION_ID: &[u8] = b"t";
+
+/// Emits the envelope shared by all queries. Dictionary keys have to be
+/// emitted in sorted order, which is why `args` comes first.
+fn emit_query<F>(
+    e: bendy::encoding::SingleItemEncoder,
+    method_name: &str,
+    tx_id: &TxId,
+    args: F,
+) -> Result<(), bendy::encoding::Error>
+where
+    F: FnOnce(bendy::encoding::SortedDictEncoder) -> Result<(), bendy::encoding::Error>,
+{
+    e.emit_dict(|mut e| {
+        e.emit_pair_with(KEY_QUERY_ARGUMENTS, |e| e.emit_dict(args))?;
+        e.emit_pair(KEY_QUERY_METHOD_NAME, method_name)?;
+
+        e.emit_pair_with(KEY_TRANSACTION_ID, |e| {
+            e.emit_bytes(tx_id.as_slice())
+        })?;
+
+        e.emit_pair(KEY_MESSAGE_TYPE, "q")?;
+
+        Ok(())
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PingRequest {
+    pub tx_id: TxId,
+    pub node_id_self: NodeId,
+}
+
+impl ToBencode for PingRequest {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        emit_query(e, "ping", &self.tx_id, |mut e| {
+            e.emit_pair_with(b"id", |e| {
+                e.emit_bytes(self.node_id_self.as_slice())
+            })
+        })
     }
 }
 
@@ -225,66 +366,324 @@ pub struct FindNodeRequest {
     pub node_id_target: NodeId,
 }
 
-const KEY_MESSAGE_TYPE: &[u8] = b"y";
-const KEY_QUERY_ARGUMENTS: &[u8] = b"a";
-const KEY_QUERY_METHOD_NAME: &[u8] = b"q";
-const KEY_RETURN_VALUES: &[u8] = b"r";
-const KEY_TRANSACTION_ID: &[u8] = b"t";
-
 impl ToBencode for FindNodeRequest {
     const MAX_DEPTH: usize = 5;
 
     fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
-        e.emit_dict(|mut e| {
-            e.emit_pair_with(KEY_QUERY_ARGUMENTS, |e| {
-                e.emit_dict(|mut e| {
-                    e.emit_pair_with(b"id", |e| {
-                        e.emit_bytes(self.node_id_self.as_slice())
-                    })?;
-
-                    e.emit_pair_with(b"target", |e| {
-                        e.emit_bytes(self.node_id_target.as_slice())
-                    })
-                })
+        emit_query(e, "find_node", &self.tx_id, |mut e| {
+            e.emit_pair_with(b"id", |e| {
+                e.emit_bytes(self.node_id_self.as_slice())
+            })?;
+
+            e.emit_pair_with(b"target", |e| {
+                e.emit_bytes(self.node_id_target.as_slice())
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetPeersRequest {
+    pub tx_id: TxId,
+    pub node_id_self: NodeId,
+    pub info_hash: NodeId,
+}
+
+impl ToBencode for GetPeersRequest {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        emit_query(e, "get_peers", &self.tx_id, |mut e| {
+            e.emit_pair_with(b"id", |e| {
+                e.emit_bytes(self.node_id_self.as_slice())
+            })?;
+
+            e.emit_pair_with(b"info_hash", |e| {
+                e.emit_bytes(self.info_hash.as_slice())
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnouncePeerRequest {
+    pub tx_id: TxId,
+    pub node_id_self: NodeId,
+    pub info_hash: NodeId,
+    /// Port to announce. When `None`, the receiving node is asked to use the
+    /// source port of the datagram instead (`implied_port`).
+    pub port: Option<u16>,
+    pub token: Vec<u8>,
+}
+
+impl ToBencode for AnnouncePeerRequest {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        emit_query(e, "announce_peer", &self.tx_id, |mut e| {
+            e.emit_pair_with(b"id", |e| {
+                e.emit_bytes(self.node_id_self.as_slice())
+            })?;
+
+            e.emit_pair(b"implied_port", i64::from(self.port.is_none()))?;
+
+            e.emit_pair_with(b"info_hash", |e| {
+                e.emit_bytes(self.info_hash.as_slice())
             })?;
 
-            e.emit_pair(KEY_QUERY_METHOD_NAME, "find_node")?;
+            e.emit_pair(b"port", self.port.unwrap_or(0))?;
+
+            e.emit_pair_with(b"token", |e| {
+                e.emit_bytes(&self.token)
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum KrpcQuery {
+    Ping(PingRequest),
+    FindNode(FindNodeRequest),
+    GetPeers(GetPeersRequest),
+    AnnouncePeer(AnnouncePeerRequest),
+}
+
+impl KrpcQuery {
+    pub fn tx_id(&self) -> &TxId {
+        match self {
+            Self::Ping(x) => &x.tx_id,
+            Self::FindNode(x) => &x.tx_id,
+            Self::GetPeers(x) => &x.tx_id,
+            Self::AnnouncePeer(x) => &x.tx_id,
+        }
+    }
+
+    /// Same query under a different transaction id.
+    pub fn with_tx_id(mut self, tx_id: TxId) -> Self {
+        match &mut self {
+            Self::Ping(x) => x.tx_id = tx_id,
+            Self::FindNode(x) => x.tx_id = tx_id,
+            Self::GetPeers(x) => x.tx_id = tx_id,
+            Self::AnnouncePeer(x) => x.tx_id = tx_id,
+        }
+
+        self
+    }
+
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            Self::Ping(_) => "ping",
+            Self::FindNode(_) => "find_node",
+            Self::GetPeers(_) => "get_peers",
+            Self::AnnouncePeer(_) => "announce_peer",
+        }
+    }
+}
+
+impl ToBencode for KrpcQuery {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        match self {
+            Self::Ping(x) => e.emit(x),
+            Self::FindNode(x) => e.emit(x),
+            Self::GetPeers(x) => e.emit(x),
+            Self::AnnouncePeer(x) => e.emit(x),
+        }
+    }
+}
+
+/// Return values of any query. Which fields are present depends on the
+/// method that was queried.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KrpcResponse {
+    pub tx_id: TxId,
+    pub node_id: Option<NodeId>,
+    pub nodes: Option<Vec<Node>>,
+    pub values: Option<Vec<NodeAddr>>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcError {
+    pub tx_id: TxId,
+    pub code: i64,
+    pub message: String,
+}
+
+impl ToBencode for KrpcResponse {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        e.emit_dict(|mut e| {
+            e.emit_pair_with(KEY_RETURN_VALUES, |e| e.emit_dict(|mut e| {
+                if let Some(id) = &self.node_id {
+                    e.emit_pair_with(b"id", |e| e.emit_bytes(id.as_slice()))?;
+                }
+
+                if let Some(nodes) = &self.nodes {
+                    let compact: Vec<u8> = nodes
+                        .iter()
+                        .flat_map(|x| [x.id.as_slice(), &x.addr.to_compact_node_id()].concat())
+                        .collect();
+
+                    e.emit_pair_with(b"nodes", |e| e.emit_bytes(&compact))?;
+                }
+
+                if let Some(token) = &self.token {
+                    e.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+                }
+
+                if let Some(values) = &self.values {
+                    e.emit_pair_with(b"values", |e| e.emit_list(|e| {
+                        for value in values.iter() {
+                            e.emit_bytes(&value.to_compact_node_id())?;
+                        }
+
+                        Ok(())
+                    }))?;
+                }
+
+                Ok(())
+            }))?;
 
             e.emit_pair_with(KEY_TRANSACTION_ID, |e| {
                 e.emit_bytes(self.tx_id.as_slice())
             })?;
 
-            e.emit_pair(KEY_MESSAGE_TYPE, "q")?;
+            e.emit_pair(KEY_MESSAGE_TYPE, "r")
+        })
+    }
+}
+
+impl KrpcError {
+    /// Malformed packet, invalid arguments or bad token (BEP 5).
+    pub const PROTOCOL_ERROR: i64 = 203;
+
+    pub const METHOD_UNKNOWN: i64 = 204;
+
+    pub fn new(tx_id: TxId, code: i64, message: impl Into<String>) -> Self {
+        Self { tx_id, code, message: message.into() }
+    }
+}
+
+impl ToBencode for KrpcError {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        e.emit_dict(|mut e| {
+            e.emit_pair_with(KEY_ERROR, |e| e.emit_list(|e| {
+                e.emit_int(self.code)?;
+                e.emit_str(&self.message)
+            }))?;
+
+            e.emit_pair_with(KEY_TRANSACTION_ID, |e| {
+                e.emit_bytes(self.tx_id.as_slice())
+            })?;
 
-            Ok(())
+            e.emit_pair(KEY_MESSAGE_TYPE, "e")
         })
     }
 }
 
+/// Our answer to a query from another node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KrpcReply {
+    Response(KrpcResponse),
+    Error(KrpcError),
+}
+
+impl ToBencode for KrpcReply {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, e: bendy::encoding::SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        match self {
+            Self::Response(x) => e.emit(x),
+            Self::Error(x) => e.emit(x),
+        }
+    }
+}
+
+/// Arguments of a query sent to us. Which fields are present depends on the
+/// method.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KrpcIncomingQuery {
+    pub tx_id: TxId,
+    pub method_name: String,
+    pub node_id: Option<NodeId>,
+    pub target: Option<NodeId>,
+    pub info_hash: Option<NodeId>,
+    pub port: Option<u16>,
+    pub implied_port: bool,
+    pub token: Option<Vec<u8>>,
+}
+
+/// Any message received from another node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KrpcInbound {
+    Query(KrpcIncomingQuery),
+    Response(KrpcResponse),
+    Error(KrpcError),
+}
+
+impl KrpcInbound {
+    pub fn from_bencode(src: &[u8]) -> Result<Self> {
+        KrpcInboundBuilder
+            ::from_bencode(src)
+            .map_err(|e| anyhow!("decoding failed: {:?}", e))?
+            .build()
+    }
+}
+
 #[derive(Debug, Default, PartialEq)]
 pub struct FindNodeResponse {
     pub tx_id: TxId,
     pub nodes: Vec<Node>,
 }
 
+impl FindNodeResponse {
+    pub fn from_bencode(src: &[u8]) -> Result<Self> {
+        let KrpcInbound::Response(res) = KrpcInbound::from_bencode(src)? else {
+            return Err(anyhow!("wrong message type"));
+        };
+
+        Self::try_from(res)
+    }
+}
+
+impl TryFrom<KrpcResponse> for FindNodeResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(value: KrpcResponse) -> Result<Self, Self::Error> {
+        let Some(nodes) = value.nodes else {
+            return Err(anyhow!("nodes missing"));
+        };
+
+        Ok(Self {
+            tx_id: value.tx_id,
+            nodes,
+        })
+    }
+}
+
 #[derive(Debug, Default)]
-struct FindNodeResponseBuilder {
+struct KrpcInboundBuilder {
     tx_id: Option<TxId>,
     message_type: Option<String>,
     node_id: Option<Vec<u8>>,
     nodes: Option<Vec<u8>>,
+    values: Option<Vec<Vec<u8>>>,
+    token: Option<Vec<u8>>,
+    error_code: Option<i64>,
+    error_message: Option<String>,
+    method_name: Option<String>,
+    target: Option<Vec<u8>>,
+    info_hash: Option<Vec<u8>>,
+    port: Option<u16>,
+    implied_port: Option<bool>,
 }
 
-impl FindNodeResponse {
-    pub fn from_bencode(src: &[u8]) -> Result<Self> {
-        FindNodeResponseBuilder
-            ::from_bencode(src)
-            .map_err(|e| anyhow!("decoding failed: {:?}", e))?
-            .build()
-    }
-}
-
-impl FindNodeResponseBuilder {
+impl KrpcInboundBuilder {
     fn set_tx_id(&mut self, value: TxId) {
         self.tx_id = Some(value);
     }
@@ -301,7 +700,43 @@ impl FindNodeResponseBuilder {
         self.nodes = Some(value);
     }
 
-    fn build(self) -> Result<FindNodeResponse> {
+    fn set_values(&mut self, value: Vec<Vec<u8>>) {
+        self.values = Some(value);
+    }
+
+    fn set_token(&mut self, value: Vec<u8>) {
+        self.token = Some(value);
+    }
+
+    fn set_error_code(&mut self, value: i64) {
+        self.error_code = Some(value);
+    }
+
+    fn set_error_message(&mut self, value: String) {
+        self.error_message = Some(value);
+    }
+
+    fn set_method_name(&mut self, value: String) {
+        self.method_name = Some(value);
+    }
+
+    fn set_target(&mut self, value: Vec<u8>) {
+        self.target = Some(value);
+    }
+
+    fn set_info_hash(&mut self, value: Vec<u8>) {
+        self.info_hash = Some(value);
+    }
+
+    fn set_port(&mut self, value: u16) {
+        self.port = Some(value);
+    }
+
+    fn set_implied_port(&mut self, value: bool) {
+        self.implied_port = Some(value);
+    }
+
+    fn build(self) -> Result<KrpcInbound> {
         let Some(tx_id) = self.tx_id else {
             return Err(anyhow!("tx_id missing"));
         };
@@ -310,15 +745,74 @@ impl FindNodeResponseBuilder {
             return Err(anyhow!("message_type missing"));
         };
 
-        if message_type != "r" {
-            return Err(anyhow!("wrong message type"));
+        match message_type.as_str() {
+            "r" => {},
+
+            "e" => {
+                return Ok(KrpcInbound::Error(KrpcError {
+                    tx_id,
+                    code: self.error_code.unwrap_or_default(),
+                    message: self.error_message.unwrap_or_default(),
+                }));
+            },
+
+            "q" => {
+                let Some(method_name) = self.method_name else {
+                    return Err(anyhow!("method name missing"));
+                };
+
+                return Ok(KrpcInbound::Query(KrpcIncomingQuery {
+                    tx_id,
+                    method_name,
+                    node_id: Self::parse_id("id", self.node_id)?,
+                    target: Self::parse_id("target", self.target)?,
+                    info_hash: Self::parse_id("info_hash", self.info_hash)?,
+                    port: self.port,
+                    implied_port: self.implied_port.unwrap_or_default(),
+                    token: self.token,
+                }));
+            },
+
+            _ => return Err(anyhow!("wrong message type")),
         }
 
-        let Some(compact_nodes) = self.nodes else {
-            return Err(anyhow!("nodes missing"));
+        let node_id = Self::parse_id("id", self.node_id)?;
+
+        let nodes = match self.nodes {
+            Some(compact_nodes) => Some(Self::parse_nodes(&compact_nodes)?),
+            None => None,
+        };
+
+        let values = match self.values {
+            Some(values) => Some(
+                values
+                    .iter()
+                    .map(|x| NodeAddr::from_compact_node_id(x))
+                    .collect::<Result<Vec<_>>>()?
+            ),
+
+            None => None,
         };
 
-        if compact_nodes.len() % NODE_LEN_BYTES != 0 {
+        Ok(KrpcInbound::Response(KrpcResponse {
+            tx_id,
+            node_id,
+            nodes,
+            values,
+            token: self.token,
+        }))
+    }
+
+    fn parse_id(name: &str, value: Option<Vec<u8>>) -> Result<Option<NodeId>> {
+        match value {
+            Some(id) if id.len() == ID_LEN_BYTES => Ok(Some(NodeId(id))),
+            Some(id) => Err(anyhow!("{} invalid length ({})", name, id.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_nodes(compact_nodes: &[u8]) -> Result<Vec<Node>> {
+        if !compact_nodes.len().is_multiple_of(NODE_LEN_BYTES) {
             return Err(anyhow!(
                 "nodes invalid length ({})",
                 compact_nodes.len()
@@ -335,17 +829,14 @@ impl FindNodeResponseBuilder {
             nodes.push(Node { id, addr });
         }
 
-        Ok(FindNodeResponse {
-            tx_id,
-            nodes,
-        })
+        Ok(nodes)
     }
 }
 
-impl FromBencode for FindNodeResponseBuilder {
+impl FromBencode for KrpcInboundBuilder {
     fn decode_bencode_object(object: bendy::decoding::Object) -> Result<Self, bendy::decoding::Error>
     where Self: Sized {
-        let mut builder = FindNodeResponseBuilder::default();
+        let mut builder = KrpcInboundBuilder::default();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -371,9 +862,79 @@ impl FromBencode for FindNodeResponseBuilder {
                                 builder.set_nodes(value.try_into_bytes()?.to_vec());
                             },
 
-                            _ => {},
-                        }
-                    }
+                            (b"values", value) => {
+                                let mut list = value.try_into_list()?;
+                                let mut values = vec![];
+
+                                while let Some(value) = list.next_object()? {
+                                    values.push(value.try_into_bytes()?.to_vec());
+                                }
+
+                                builder.set_values(values);
+                            },
+
+                            (b"token", value) => {
+                                builder.set_token(value.try_into_bytes()?.to_vec());
+                            },
+
+                            _ => {},
+                        }
+                    }
+                },
+
+                (KEY_QUERY_METHOD_NAME, value) => {
+                    builder.set_method_name(String::decode_bencode_object(value)?);
+                },
+
+                (KEY_QUERY_ARGUMENTS, value) => {
+                    let mut dict = value.try_into_dictionary()?;
+
+                    while let Some(pair) = dict.next_pair()? {
+                        match pair {
+                            (b"id", value) => {
+                                builder.set_node_id(value.try_into_bytes()?.to_vec());
+                            },
+
+                            (b"target", value) => {
+                                builder.set_target(value.try_into_bytes()?.to_vec());
+                            },
+
+                            (b"info_hash", value) => {
+                                builder.set_info_hash(value.try_into_bytes()?.to_vec());
+                            },
+
+                            (b"port", value) => {
+                                builder.set_port(u16::decode_bencode_object(value)?);
+                            },
+
+                            (b"implied_port", value) => {
+                                builder.set_implied_port(i64::decode_bencode_object(value)? != 0);
+                            },
+
+                            (b"token", value) => {
+                                builder.set_token(value.try_into_bytes()?.to_vec());
+                            },
+
+                            _ => {},
+                        }
+                    }
+                },
+
+                (KEY_ERROR, value) => {
+                    let mut list = value.try_into_list()?;
+
+                    if let Some(value) = list.next_object()? {
+                        builder.set_error_code(i64::decode_bencode_object(value)?);
+                    }
+
+                    let message = match list.next_object()? {
+                        Some(value) => value.try_into_bytes()?,
+                        None => b"",
+                    };
+
+                    builder.set_error_message(
+                        String::from_utf8_lossy(message).into_owned()
+                    );
                 },
 
                 _ => {},
@@ -384,87 +945,314 @@ impl FromBencode for FindNodeResponseBuilder {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct KrpcRequest {
     pub dst: NodeAddr,
-    pub payload: FindNodeRequest,
-    pub in_progress: bool,
+    pub payload: KrpcQuery,
+
+    /// Taken by the sender once the request is sent. Removing the request
+    /// releases it, so the next one can be sent.
+    pub permit: Option<OwnedSemaphorePermit>,
+
+    /// How many times this request has been retried so far.
+    pub attempt: u32,
+
+    /// When the request went out, for measuring the round trip.
+    pub sent_at: Option<Instant>,
+
+    /// Entered by the sender and the main loop whenever they handle the
+    /// request. A child of the span the request was created in, e.g. a
+    /// lookup.
+    pub span: Span,
+}
+
+impl KrpcRequest {
+    pub fn new(dst: NodeAddr, payload: KrpcQuery) -> Self {
+        let span = debug_span!(
+            "query",
+            method = payload.method_name(),
+            %dst,
+            tx_id = field::Empty,
+        );
+
+        Self {
+            dst,
+            payload,
+            permit: None,
+            attempt: 0,
+            sent_at: None,
+            span,
+        }
+    }
+
+    /// The next attempt of this request. Gets a fresh transaction id once
+    /// inserted.
+    pub fn retry(self) -> Self {
+        let span = debug_span!(
+            parent: &self.span,
+            "retry",
+            attempt = self.attempt + 1,
+            tx_id = field::Empty,
+        );
+
+        Self {
+            dst: self.dst,
+            payload: self.payload,
+            permit: None,
+            attempt: self.attempt + 1,
+            sent_at: None,
+            span,
+        }
+    }
+
+    /// Inserts the request under a random transaction id that no other
+    /// in-flight request uses, and returns that id.
+    pub fn insert_into(mut self, requests: &mut HashMap<TxId, KrpcRequest>) -> TxId {
+        let tx_id = TxId::unused_in(requests);
+
+        self.payload = self.payload.with_tx_id(tx_id.clone());
+        self.span.record("tx_id", field::display(&tx_id));
+        requests.insert(tx_id.clone(), self);
+
+        tx_id
+    }
+
+    /// Removes the request with `tx_id`, but only if it was sent to `src`.
+    /// Responses are matched on both, so a node can't answer requests that
+    /// were sent to another one.
+    pub fn remove_matching(
+        requests: &mut HashMap<TxId, KrpcRequest>,
+        tx_id: &TxId,
+        src: &NodeAddr,
+    ) -> Option<KrpcRequest> {
+        if requests.get(tx_id)?.dst != *src {
+            return None;
+        }
+
+        requests.remove(tx_id)
+    }
+
+    /// Span of the request `msg` is about, if it's still in flight.
+    pub fn span_of(requests: &HashMap<TxId, KrpcRequest>, msg: &KrpcMessage) -> Span {
+        msg.tx_id()
+            .and_then(|x| requests.get(x))
+            .map(|x| x.span.clone())
+            .unwrap_or_else(Span::none)
+    }
+}
+
+/// Counters shared between the sender, the receiver and the main loop.
+#[derive(Debug, Default)]
+pub struct KrpcStats {
+    pub queries_sent: AtomicU64,
+    pub send_errors: AtomicU64,
+    pub responses: AtomicU64,
+    pub errors: AtomicU64,
+    pub timeouts: AtomicU64,
+    pub retries: AtomicU64,
+    pub queries_received: AtomicU64,
+    pub replies_sent: AtomicU64,
+
+    /// Inbound packets dropped because their source went over its limit.
+    pub dropped_rate_limited: AtomicU64,
+
+    /// Inbound packets dropped because their source is blocked.
+    pub dropped_blocked: AtomicU64,
+
+    /// Inbound packets dropped because their source is on the blocklist.
+    pub dropped_blocklisted: AtomicU64,
+
+    pub queries_sent_by_method: MethodCounter,
+    pub queries_received_by_method: MethodCounter,
+    pub errors_by_code: ErrorCodeCounter,
+
+    /// Time from sending a query to its answer.
+    pub rtt: Histogram,
+    pub lookup_duration: Histogram,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KrpcStatsSnapshot {
+    pub queries_sent: u64,
+    pub send_errors: u64,
+    pub responses: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub retries: u64,
+    pub queries_received: u64,
+    pub replies_sent: u64,
+    pub dropped_rate_limited: u64,
+    pub dropped_blocked: u64,
+    pub dropped_blocklisted: u64,
+}
+
+impl KrpcStats {
+    pub fn add(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> KrpcStatsSnapshot {
+        KrpcStatsSnapshot {
+            queries_sent: self.queries_sent.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            responses: self.responses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            queries_received: self.queries_received.load(Ordering::Relaxed),
+            replies_sent: self.replies_sent.load(Ordering::Relaxed),
+            dropped_rate_limited: self.dropped_rate_limited.load(Ordering::Relaxed),
+            dropped_blocked: self.dropped_blocked.load(Ordering::Relaxed),
+            dropped_blocklisted: self.dropped_blocklisted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl KrpcStatsSnapshot {
+    pub fn queries_per_second(&self, elapsed: Duration) -> f64 {
+        self.queries_sent as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    }
 }
 
 #[derive(Debug)]
 pub enum KrpcMessage {
     Request(TxId),
-    Response(FindNodeResponse),
+    Response(NodeAddr, KrpcResponse),
+    Error(NodeAddr, KrpcError),
     ResponseTimeout(TxId),
     SendSuccess(TxId),
     SendError(TxId),
-    #[allow(dead_code)] Exit,
+
+    /// Query from another node, to be answered by the main loop.
+    Query(NodeAddr, KrpcIncomingQuery),
+
+    /// Answer to a query, sent as is by the sender.
+    Reply(NodeAddr, KrpcReply),
+
+    /// Shut down after a signal.
+    Exit(Signal),
+}
+
+impl KrpcMessage {
+    /// Transaction of the request this message is about, if any.
+    pub fn tx_id(&self) -> Option<&TxId> {
+        match self {
+            Self::Request(tx_id)
+            | Self::ResponseTimeout(tx_id)
+            | Self::SendSuccess(tx_id)
+            | Self::SendError(tx_id) => Some(tx_id),
+
+            Self::Response(_, res) => Some(&res.tx_id),
+            Self::Error(_, err) => Some(&err.tx_id),
+            Self::Query(..) | Self::Reply(..) | Self::Exit(_) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct KrpcSender {
     pub requests: Arc<RwLock<HashMap<TxId, KrpcRequest>>>,
-    pub concurrency: usize,
+
+    /// One permit per request that may be in flight at once.
+    pub permits: Arc<Semaphore>,
+
+    /// Caps packets and bytes per second across all requests.
+    pub rate_limiter: RateLimiter,
+    pub stats: Arc<KrpcStats>,
     pub sender_rx: mpsc::Receiver<KrpcMessage>,
     pub main_tx: mpsc::Sender<KrpcMessage>,
     pub sock: Arc<dyn KrpcSocket>,
 }
 
 impl KrpcSender {
-    pub fn spawn(mut self) {
-        tokio::spawn(async move { self.run().await });
+    pub fn spawn(mut self) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move { self.run().await })
     }
 
     async fn run(&mut self) -> Result<()> {
-        while let Some(msg) = self.sender_rx.recv().await {
-            self.handle_message(msg).await?
-        }
+        // Requests waiting for a permit. They wait here rather than in the
+        // channel, so replies and new requests are still taken meanwhile.
+        let mut waiting = VecDeque::new();
 
-        Ok(())
+        loop {
+            let permits = self.permits.clone();
+
+            tokio::select! {
+                msg = self.sender_rx.recv() => match msg {
+                    Some(msg) => self.handle_message(msg, &mut waiting).await?,
+                    None => return Ok(()),
+                },
+
+                permit = permits.acquire_owned(), if !waiting.is_empty() => {
+                    let tx_id = waiting.pop_front().unwrap();
+                    self.send_waiting(tx_id, permit?).await?;
+                },
+            }
+        }
     }
 
-    async fn handle_message(&self, msg: KrpcMessage) -> Result<()> {
+    async fn handle_message(&mut self, msg: KrpcMessage, waiting: &mut VecDeque<TxId>) -> Result<()> {
         debug!(?msg, "sender: recv");
 
-        let KrpcMessage::Request(tx_id) = msg else {
-            return Ok(());
-        };
+        match msg {
+            KrpcMessage::Request(tx_id) => waiting.push_back(tx_id),
+            KrpcMessage::Reply(dst, reply) => self.send_reply(dst, reply).await?,
+            _ => {},
+        }
 
-        self.wait_until_below_concurrency_limit().await;
+        Ok(())
+    }
 
-        let req = {
+    async fn send_waiting(&mut self, tx_id: TxId, permit: OwnedSemaphorePermit) -> Result<()> {
+        let (dst, payload, span) = {
             let mut requests = self.requests.write().await;
-            let req_mut = requests.get_mut(&tx_id).unwrap();
-            req_mut.in_progress = true;
-            req_mut.clone()
+
+            let Some(req) = requests.get_mut(&tx_id) else {
+                debug!(?tx_id, "sender: request is gone --> ignore");
+                return Ok(());
+            };
+
+            req.permit = Some(permit);
+            (req.dst.clone(), req.payload.clone(), req.span.clone())
         };
 
-        let data = FindNodeRequest::to_bencode(&req.payload).unwrap();
+        self.send_request(tx_id, dst, payload).instrument(span).await
+    }
+
+    async fn send_request(&mut self, tx_id: TxId, dst: NodeAddr, payload: KrpcQuery) -> Result<()> {
+        let data = payload.to_bencode().unwrap();
 
-        match self.sock.send_to(&data, req.dst.to_string()).await {
+        self.rate_limiter.acquire(data.len()).await;
+
+        match self.sock.send_to(&data, dst.to_string()).await {
             Ok(_) => {
                 debug!(
-                    dst = ?req.dst,
-                    req = ?req.payload,
+                    ?dst,
+                    req = ?payload,
                     "sender: send ok"
                 );
 
+                KrpcStats::add(&self.stats.queries_sent);
+                self.stats.queries_sent_by_method.add(payload.method_name());
+
                 self.main_tx
-                    .send(KrpcMessage::SendSuccess(req.payload.tx_id))
+                    .send(KrpcMessage::SendSuccess(tx_id))
                     .await
                     .unwrap();
             },
 
             Err(e) => {
                 error!(
-                    dst = ?req.dst,
-                    req = ?req.payload,
+                    ?dst,
+                    req = ?payload,
                     err = ?e,
                     "sender: send error"
                 );
 
+                KrpcStats::add(&self.stats.send_errors);
+
                 self.main_tx
-                    .send(KrpcMessage::SendError(req.payload.tx_id))
+                    .send(KrpcMessage::SendError(tx_id))
                     .await
                     .unwrap();
             },
@@ -472,24 +1260,29 @@ impl KrpcSender {
 
         Ok(())
     }
+}
 
-    async fn wait_until_below_concurrency_limit(&self) {
-        loop {
-            let count = {
-                self.requests
-                    .read()
-                    .await
-                    .iter()
-                    .filter(|(_k, v)| v.in_progress)
-                    .count()
-            };
+impl KrpcSender {
+    /// Replies don't wait for a permit, as they don't expect an answer. They
+    /// still count towards the rate limit.
+    async fn send_reply(&mut self, dst: NodeAddr, reply: KrpcReply) -> Result<()> {
+        let data = reply.to_bencode().unwrap();
 
-            if count < self.concurrency {
-                break;
-            }
+        self.rate_limiter.acquire(data.len()).await;
 
-            sleep(Duration::from_millis(100)).await;
+        match self.sock.send_to(&data, dst.to_string()).await {
+            Ok(_) => {
+                debug!(?dst, ?reply, "sender: reply ok");
+                KrpcStats::add(&self.stats.replies_sent);
+            },
+
+            Err(e) => {
+                error!(?dst, ?reply, err = ?e, "sender: reply error");
+                KrpcStats::add(&self.stats.send_errors);
+            },
         }
+
+        Ok(())
     }
 }
 
@@ -497,38 +1290,124 @@ impl KrpcSender {
 pub struct KrpcReceiver {
     pub sock: Arc<dyn KrpcSocket>,
     pub main_tx: mpsc::Sender<KrpcMessage>,
+    pub events: EventSender,
+    pub stats: Arc<KrpcStats>,
+
+    /// Drops packets from sources that send too many of them.
+    pub rate_limiter: InboundRateLimiter,
+    pub blocklist: Arc<Blocklist>,
 }
 
 impl KrpcReceiver {
-    pub fn spawn(self) {
-        tokio::spawn(async move { self.run().await });
+    pub fn spawn(mut self) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move { self.run().await })
     }
 
-    async fn run(&self) -> Result<()> {
+    async fn run(&mut self) -> Result<()> {
         let mut buf = [0; 4096];
 
         loop {
             let (len, addr) = self.sock.recv_from(&mut buf).await.unwrap();
+
+            if !self.allow(addr) {
+                continue;
+            }
+
             let data = &buf[0..len];
-            let data_hex = hex::encode(data);
 
-            debug!(src = ?addr, ?len, ?data_hex, "receiver: recv");
+            debug!(src = ?addr, ?len, data_hex = %Hex(data), "receiver: recv");
+
+            self.handle_data(data, addr).await?;
+        }
+    }
+
+    /// Checks the source against the blocklist and its rate limit, before
+    /// spending any time on the packet.
+    fn allow(&mut self, src: core::net::SocketAddr) -> bool {
+        if let core::net::IpAddr::V4(ip) = src.ip() {
+            if self.blocklist.blocks_ip(&ip) {
+                KrpcStats::add(&self.stats.dropped_blocklisted);
+                return false;
+            }
+        }
+
+        match self.rate_limiter.check(src.ip()) {
+            InboundVerdict::Allow => true,
+
+            InboundVerdict::Drop => {
+                KrpcStats::add(&self.stats.dropped_rate_limited);
+                false
+            },
 
-            self.handle_data(data).await?;
+            InboundVerdict::Block(duration) => {
+                debug!(?src, ?duration, "receiver: source over limit --> block");
+
+                KrpcStats::add(&self.stats.dropped_rate_limited);
+                self.events.emit(DhtEvent::SourceBlocked { ip: src.ip(), duration });
+                false
+            },
+
+            InboundVerdict::Blocked => {
+                KrpcStats::add(&self.stats.dropped_blocked);
+                false
+            },
         }
     }
 
-    async fn handle_data(&self, data: &[u8]) -> Result<()> {
-        let Ok(res) = FindNodeResponse::from_bencode(data) else {
-            return Ok(()); // only this message type is supported
+    async fn handle_data(&self, data: &[u8], src: core::net::SocketAddr) -> Result<()> {
+        let Ok(inbound) = KrpcInbound::from_bencode(data) else {
+            return Ok(());
+        };
+
+        let Ok(src_addr) = NodeAddr::try_from(src) else {
+            return Ok(()); // only ipv4 is supported
+        };
+
+        let msg = match inbound {
+            KrpcInbound::Query(query) => {
+                self.events.emit(DhtEvent::QueryReceived {
+                    src,
+                    method: query.method_name.clone(),
+                    node_id: query.node_id.clone(),
+                });
+
+                KrpcStats::add(&self.stats.queries_received);
+                self.stats.queries_received_by_method.add(&query.method_name);
+                KrpcMessage::Query(src_addr, query)
+            },
+
+            KrpcInbound::Response(res) => {
+                debug!(%src, tx_id = %res.tx_id, "receiver: response");
+                KrpcStats::add(&self.stats.responses);
+                KrpcMessage::Response(src_addr, res)
+            },
+
+            KrpcInbound::Error(err) => {
+                debug!(%src, tx_id = %err.tx_id, code = err.code, "receiver: error");
+                KrpcStats::add(&self.stats.errors);
+                self.stats.errors_by_code.add(err.code);
+                KrpcMessage::Error(src_addr, err)
+            },
         };
 
-        self.main_tx.send(KrpcMessage::Response(res)).await?;
+        self.main_tx.send(msg).await?;
 
         Ok(())
     }
 }
 
+/// Formats bytes as hex only when the event is actually logged.
+struct Hex<'a>(&'a [u8]);
+
+impl std::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
 
 #[async_trait]
 pub trait KrpcSocket: std::fmt::Debug + Send + Sync + 'static{
@@ -567,6 +1446,7 @@ impl KrpcSocket for KrpcSocketStub {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn calculates_distance() {
@@ -583,6 +1463,15 @@ mod tests {
         assert!(Distance(vec![0, 0, 0]) < Distance(vec![1, 0, 0]));
     }
 
+    #[test]
+    fn generates_random_ids_with_given_prefix() {
+        let id = NodeId::random(ID_LEN_BYTES);
+
+        for lcp in [0, 1, 7, 8, 9, 100, ID_LEN_BITS - 1] {
+            assert_eq!(id.random_with_lcp(lcp).distance_to(&id).lcp(), lcp);
+        }
+    }
+
     #[test]
     fn calculates_kongest_common_prefix() {
         assert_eq!(Distance(vec![0b00000000, 0b00000000]).lcp(), 16);
@@ -639,6 +1528,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encodes_announce_peer_request() {
+        let req = AnnouncePeerRequest {
+            tx_id: TxId::from_str("aa").unwrap(),
+            node_id_self: NodeId::from_str("Aihoi6iC6Oowo0quor1j").unwrap(),
+            info_hash: NodeId::from_str("Viefohchaog3shoh7qui").unwrap(),
+            port: Some(6881),
+            token: b"aoeusnth".to_vec(),
+        };
+
+        similar_asserts::assert_eq!(
+            String::from_utf8(
+                req.to_bencode().unwrap()
+            ).unwrap(),
+            "d1:ad2:id20:Aihoi6iC6Oowo0quor1j12:implied_porti0e9:info_hash20:Viefohchaog3shoh7qui4:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe".to_string()
+        );
+    }
+
     #[test]
     fn decodes_find_node_response_with_single_node() {
         // Response = {
@@ -712,4 +1619,301 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn decodes_get_peers_response_with_values() {
+        // Response = {
+        //   "t": "aa"
+        //   "y": "r",
+        //   "r": {
+        //     "id": "Viefohchaog3shoh7qui",
+        //     "token": "aoeusnth",
+        //     "values": ["abcdef", "bcdefg"]
+        //   }
+        // }
+
+        similar_asserts::assert_eq!(
+            KrpcInbound::from_bencode(
+                "d1:rd2:id20:Viefohchaog3shoh7qui5:token8:aoeusnth6:valuesl6:abcdef6:bcdefgee1:t2:aa1:y1:re".as_bytes()
+            ).unwrap(),
+
+            KrpcInbound::Response(KrpcResponse {
+                tx_id: TxId::from_str("aa").unwrap(),
+                node_id: Some(NodeId::from_str("Viefohchaog3shoh7qui").unwrap()),
+                nodes: None,
+                values: Some(vec![
+                    NodeAddr::from_str("97.98.99.100:25958").unwrap(),
+                    NodeAddr::from_str("98.99.100.101:26215").unwrap(),
+                ]),
+                token: Some(b"aoeusnth".to_vec()),
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_empty_values() -> Result<()> {
+        let res = KrpcResponse {
+            tx_id: TxId::from_u16(1),
+            values: Some(vec![]),
+            ..Default::default()
+        };
+
+        let data = res.to_bencode().unwrap();
+        assert_eq!(KrpcInbound::from_bencode(&data)?, KrpcInbound::Response(res));
+
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_responses_and_errors() -> Result<()> {
+        let res = KrpcResponse {
+            tx_id: TxId::from_u16(1),
+            node_id: Some(NodeId::random(ID_LEN_BYTES)),
+            nodes: Some(vec![Node {
+                id: NodeId::random(ID_LEN_BYTES),
+                addr: NodeAddr::from_str("1.2.3.4:6881")?,
+            }]),
+            values: Some(vec![NodeAddr::from_str("5.6.7.8:6882")?]),
+            token: Some(b"token".to_vec()),
+        };
+
+        let data = KrpcReply::Response(res.clone()).to_bencode().unwrap();
+        assert_eq!(KrpcInbound::from_bencode(&data)?, KrpcInbound::Response(res));
+
+        let err = KrpcError::new(TxId::from_u16(2), KrpcError::METHOD_UNKNOWN, "method unknown");
+        let data = KrpcReply::Error(err.clone()).to_bencode().unwrap();
+
+        assert_eq!(data, b"d1:eli204e14:method unknowne1:t2:\x00\x021:y1:ee");
+        assert_eq!(KrpcInbound::from_bencode(&data)?, KrpcInbound::Error(err));
+
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_error() {
+        // Error = {
+        //   "t": "aa"
+        //   "y": "e",
+        //   "e": [201, "A Generic Error Ocurred"]
+        // }
+
+        similar_asserts::assert_eq!(
+            KrpcInbound::from_bencode(
+                "d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee".as_bytes()
+            ).unwrap(),
+
+            KrpcInbound::Error(KrpcError {
+                tx_id: TxId::from_str("aa").unwrap(),
+                code: 201,
+                message: "A Generic Error Ocurred".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_find_node_query() {
+        // Query = {
+        //   "t": "aa"
+        //   "y": "q",
+        //   "q": "find_node",
+        //   "a": {
+        //     "id": "Aihoi6iC6Oowo0quor1j",
+        //     "target": "Viefohchaog3shoh7qui"
+        //   }
+        // }
+
+        similar_asserts::assert_eq!(
+            KrpcInbound::from_bencode(
+                "d1:ad2:id20:Aihoi6iC6Oowo0quor1j6:target20:Viefohchaog3shoh7quie1:q9:find_node1:t2:aa1:y1:qe".as_bytes()
+            ).unwrap(),
+
+            KrpcInbound::Query(KrpcIncomingQuery {
+                tx_id: TxId::from_str("aa").unwrap(),
+                method_name: "find_node".to_string(),
+                node_id: Some(NodeId::from_str("Aihoi6iC6Oowo0quor1j").unwrap()),
+                target: Some(NodeId::from_str("Viefohchaog3shoh7qui").unwrap()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn removes_request_only_when_source_matches() -> Result<()> {
+        let mut requests = HashMap::new();
+        let tx_id = KrpcRequest::new(
+            NodeAddr::from_str("127.0.0.1:1000")?,
+            KrpcQuery::Ping(PingRequest {
+                tx_id: TxId::default(),
+                node_id_self: NodeId::from_str("abcdefghij0123456789")?,
+            }),
+        ).insert_into(&mut requests);
+
+        assert_eq!(tx_id.as_slice().len(), TX_ID_LEN_BYTES);
+        assert_eq!(requests[&tx_id].payload.tx_id(), &tx_id);
+
+        let other = NodeAddr::from_str("127.0.0.1:1001")?;
+        assert!(KrpcRequest::remove_matching(&mut requests, &tx_id, &other).is_none());
+
+        let dst = NodeAddr::from_str("127.0.0.1:1000")?;
+        assert!(KrpcRequest::remove_matching(&mut requests, &tx_id, &dst).is_some());
+        assert!(requests.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sender_waits_for_permit_until_request_is_removed() -> Result<()> {
+        let requests = Arc::new(RwLock::new(HashMap::new()));
+        let (sender_tx, sender_rx) = mpsc::channel::<KrpcMessage>(1024);
+        let (main_tx, mut main_rx) = mpsc::channel::<KrpcMessage>(1024);
+
+        for tx_id in ["aa", "ab"] {
+            let tx_id = TxId::from_str(tx_id)?;
+
+            requests.write().await.insert(tx_id.clone(), KrpcRequest::new(
+                NodeAddr::from_str("127.0.0.1:1000")?,
+                KrpcQuery::Ping(PingRequest {
+                    tx_id: tx_id.clone(),
+                    node_id_self: NodeId::random(ID_LEN_BYTES),
+                }),
+            ));
+
+            sender_tx.send(KrpcMessage::Request(tx_id)).await?;
+        }
+
+        KrpcSender {
+            requests: requests.clone(),
+            permits: Arc::new(Semaphore::new(1)),
+            rate_limiter: RateLimiter::default(),
+            stats: Arc::default(),
+            sender_rx,
+            main_tx,
+            sock: Arc::new(KrpcSocketStub),
+        }.spawn();
+
+        assert!(matches!(
+            main_rx.recv().await,
+            Some(KrpcMessage::SendSuccess(tx_id)) if tx_id == TxId::from_str("aa")?
+        ));
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), main_rx.recv())
+                .await
+                .is_err()
+        );
+
+        requests.write().await.remove(&TxId::from_str("aa")?);
+
+        assert!(matches!(
+            main_rx.recv().await,
+            Some(KrpcMessage::SendSuccess(tx_id)) if tx_id == TxId::from_str("ab")?
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sender_sends_replies_while_requests_wait_for_permit() -> Result<()> {
+        let requests = Arc::new(RwLock::new(HashMap::new()));
+        let permits = Arc::new(Semaphore::new(1));
+        let stats = Arc::new(KrpcStats::default());
+        let (sender_tx, sender_rx) = mpsc::channel::<KrpcMessage>(1);
+        let (main_tx, _main_rx) = mpsc::channel::<KrpcMessage>(1024);
+
+        // Every permit is in use, e.g. by a request waiting for its answer.
+        let _permit = permits.clone().acquire_owned().await?;
+
+        KrpcSender {
+            requests: requests.clone(),
+            permits,
+            rate_limiter: RateLimiter::default(),
+            stats: stats.clone(),
+            sender_rx,
+            main_tx,
+            sock: Arc::new(KrpcSocketStub),
+        }.spawn();
+
+        // Fails rather than hangs if the requests hold up the channel.
+        tokio::time::timeout(Duration::from_secs(1), async {
+            for tx_id in ["aa", "ab", "ac"] {
+                sender_tx.send(KrpcMessage::Request(TxId::from_str(tx_id)?)).await?;
+            }
+
+            let reply = KrpcReply::Error(KrpcError::new(TxId::from_str("zz")?, KrpcError::PROTOCOL_ERROR, "test"));
+            sender_tx.send(KrpcMessage::Reply(NodeAddr::from_str("127.0.0.1:1000")?, reply)).await?;
+
+            while stats.replies_sent.load(Ordering::Relaxed) == 0 {
+                tokio::task::yield_now().await;
+            }
+
+            Ok::<_, anyhow::Error>(())
+        }).await??;
+
+        assert_eq!(stats.queries_sent.load(Ordering::Relaxed), 0);
+
+        Ok(())
+    }
+
+    fn node_id() -> impl Strategy<Value = NodeId> {
+        any::<[u8; ID_LEN_BYTES]>().prop_map(NodeId::new)
+    }
+
+    /// `a + b`, one byte longer than both so it can't overflow.
+    fn add(a: &Distance, b: &Distance) -> Distance {
+        let mut sum = vec![0; a.0.len() + 1];
+        let mut carry = 0;
+
+        for (index, (x, y)) in a.0.iter().zip(b.0.iter()).enumerate().rev() {
+            let value = *x as u16 + *y as u16 + carry;
+            sum[index + 1] = value as u8;
+            carry = value >> 8;
+        }
+
+        sum[0] = carry as u8;
+        Distance(sum)
+    }
+
+    proptest! {
+        #[test]
+        fn distance_is_symmetric(a in node_id(), b in node_id()) {
+            prop_assert_eq!(a.distance_to(&b), b.distance_to(&a));
+        }
+
+        #[test]
+        fn distance_is_zero_only_to_itself(a in node_id(), b in node_id()) {
+            prop_assert_eq!(a.distance_to(&a), Distance(vec![0; ID_LEN_BYTES]));
+            prop_assert_eq!(a.distance_to(&a).lcp(), ID_LEN_BITS);
+            prop_assert_eq!(a.distance_to(&b).lcp() == ID_LEN_BITS, a == b);
+        }
+
+        #[test]
+        fn distance_obeys_triangle_inequality(a in node_id(), b in node_id(), c in node_id()) {
+            let direct = add(&a.distance_to(&c), &Distance(vec![0; ID_LEN_BYTES]));
+            let detour = add(&a.distance_to(&b), &b.distance_to(&c));
+
+            prop_assert!(direct <= detour, "{:?} > {:?}", direct, detour);
+        }
+
+        #[test]
+        fn lcp_counts_leading_zero_bits(bytes in any::<[u8; ID_LEN_BYTES]>()) {
+            let bits: String = bytes.iter().map(|x| format!("{:08b}", x)).collect();
+            let expected = bits.find('1').unwrap_or(ID_LEN_BITS);
+
+            prop_assert_eq!(Distance(bytes.to_vec()).lcp(), expected);
+        }
+
+        #[test]
+        fn id_with_lcp_has_requested_lcp(id in node_id(), rest in node_id(), lcp in 0..ID_LEN_BITS) {
+            let other = id.with_lcp(lcp, rest.clone());
+
+            prop_assert_eq!(other.distance_to(&id).lcp(), lcp);
+
+            // Past the prefix and the bit that differs, it's all `rest`.
+            let bits = |x: &NodeId| -> String {
+                x.as_slice().iter().map(|x| format!("{:08b}", x)).collect()
+            };
+
+            prop_assert_eq!(&bits(&other)[lcp + 1..], &bits(&rest)[lcp + 1..]);
+        }
+    }
 }
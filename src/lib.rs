@@ -1,2 +1,23 @@
 pub mod app;
+pub mod blocklist;
+pub mod bootstrap;
+pub mod cli;
+pub mod config;
+pub mod control;
+pub mod dht;
+pub mod events;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod krpc;
+pub mod logging;
+pub mod metrics;
+pub mod output;
+pub mod peers;
+pub mod ratelimit;
+pub mod routing;
+pub mod serve;
+pub mod shutdown;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod timeouts;
+pub mod vnet;
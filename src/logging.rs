@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use tracing_subscriber::EnvFilter;
+
+/// Log filter when neither `--log-level` nor `RUST_LOG` is set.
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable, with the spans an event happened in as prefix.
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the fields of every span.
+    Json,
+}
+
+/// How to log. Logs always go to stderr.
+#[derive(Debug, Args, Clone)]
+pub struct LogArgs {
+    /// Which events to log, in `RUST_LOG` syntax, e.g. `debug` or
+    /// `info,kademliar::krpc=trace`. Overrides `RUST_LOG`.
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Format of the log lines.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text, env = "KADEMLIAR_LOG_FORMAT")]
+    pub log_format: LogFormat,
+}
+
+impl LogArgs {
+    /// `--log-level`, else `RUST_LOG`, else [`DEFAULT_LOG_FILTER`].
+    pub fn filter(&self) -> Result<EnvFilter> {
+        self.filter_or(DEFAULT_LOG_FILTER)
+    }
+
+    /// Same as [`LogArgs::filter`], with another default.
+    pub fn filter_or(&self, default: &str) -> Result<EnvFilter> {
+        let directives = match (&self.log_level, std::env::var(EnvFilter::DEFAULT_ENV)) {
+            (Some(value), _) => value.clone(),
+            (None, Ok(value)) => value,
+            (None, Err(_)) => default.into(),
+        };
+
+        EnvFilter::try_new(&directives)
+            .with_context(|| format!("invalid log filter: {}", directives))
+    }
+
+    /// Installs the global subscriber. Fails if there already is one.
+    pub fn init(&self) -> Result<()> {
+        self.init_or(DEFAULT_LOG_FILTER)
+    }
+
+    /// Same as [`LogArgs::init`], with another default filter.
+    pub fn init_or(&self, default: &str) -> Result<()> {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(self.filter_or(default)?)
+            .with_writer(std::io::stderr);
+
+        let result = match self.log_format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+        };
+
+        result.map_err(|e| anyhow!(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_log_level_over_env() {
+        let args = LogArgs {
+            log_level: Some("kademliar::krpc=trace".into()),
+            log_format: LogFormat::Json,
+        };
+
+        assert_eq!(args.filter().unwrap().to_string(), "kademliar::krpc=trace");
+
+        let args = LogArgs { log_level: Some("kademliar=loud".into()), ..args };
+        assert!(args.filter().is_err());
+    }
+}
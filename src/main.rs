@@ -1,14 +1,16 @@
 use anyhow::Result;
-use tracing_subscriber::FmtSubscriber;
+use kademliar::shutdown::Interrupted;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(tracing::Level::DEBUG)
-        .finish();
+    if let Err(e) = kademliar::cli::main().await {
+        // Everything was written already, only the status is left.
+        if let Some(Interrupted(signal)) = e.downcast_ref() {
+            std::process::exit(signal.exit_code());
+        }
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("setting default subscriber failed");
+        return Err(e);
+    }
 
-    kademliar::app::App::main().await
+    Ok(())
 }
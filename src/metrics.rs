@@ -0,0 +1,335 @@
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::dht::*;
+use crate::krpc::*;
+use crate::routing::*;
+
+/// Label for anything we don't know by name, so a remote node can't blow up
+/// the number of series.
+const OTHER: &str = "other";
+
+const METHODS: [&str; 5] = ["ping", "find_node", "get_peers", "announce_peer", OTHER];
+
+/// Error codes from BEP 5.
+const ERROR_CODES: [&str; 5] = ["201", "202", "203", "204", OTHER];
+
+/// Upper bounds of the duration buckets, in seconds.
+pub const DURATION_BUCKETS: [f64; 11] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counts by a label with a fixed set of values.
+#[derive(Debug)]
+pub struct LabeledCounter<const N: usize> {
+    labels: &'static [&'static str; N],
+    counts: [AtomicU64; N],
+}
+
+impl<const N: usize> LabeledCounter<N> {
+    fn new(labels: &'static [&'static str; N]) -> Self {
+        Self {
+            labels,
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Counts `label`, or `other` if it isn't one of the known values.
+    pub fn add(&self, label: &str) {
+        let index = self
+            .labels
+            .iter()
+            .position(|x| *x == label)
+            .unwrap_or(N - 1);
+
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, label: &str) -> u64 {
+        self.labels
+            .iter()
+            .position(|x| *x == label)
+            .map(|x| self.counts[x].load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.labels
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(label, count)| (*label, count.load(Ordering::Relaxed)))
+    }
+}
+
+/// Counts by KRPC method.
+pub type MethodCounter = LabeledCounter<5>;
+
+impl Default for MethodCounter {
+    fn default() -> Self {
+        Self::new(&METHODS)
+    }
+}
+
+/// Counts by KRPC error code.
+#[derive(Debug)]
+pub struct ErrorCodeCounter(LabeledCounter<5>);
+
+impl Default for ErrorCodeCounter {
+    fn default() -> Self {
+        Self(LabeledCounter::new(&ERROR_CODES))
+    }
+}
+
+impl ErrorCodeCounter {
+    pub fn add(&self, code: i64) {
+        self.0.add(&code.to_string());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.0.iter()
+    }
+}
+
+/// Durations in [`DURATION_BUCKETS`].
+#[derive(Debug)]
+pub struct Histogram {
+    /// Not cumulative, one more than there are buckets for `+Inf`.
+    counts: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let index = DURATION_BUCKETS
+            .iter()
+            .position(|x| secs <= *x)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|x| x.load(Ordering::Relaxed)).sum()
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+
+        for (index, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+
+            let le = match DURATION_BUCKETS.get(index) {
+                Some(x) => x.to_string(),
+                None => "+Inf".into(),
+            };
+
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_labeled<'a>(
+    out: &mut String,
+    kind: &str,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+/// Everything in the Prometheus text format.
+pub fn render(stats: &KrpcStats, table: &RoutingTable, in_flight: usize) -> String {
+    let s = stats.snapshot();
+    let mut out = String::new();
+
+    write_labeled(
+        &mut out, "counter",
+        "kademliar_queries_sent_total", "Queries sent, by method.",
+        "method", stats.queries_sent_by_method.iter(),
+    );
+
+    write_labeled(
+        &mut out, "counter",
+        "kademliar_queries_received_total", "Queries received, by method.",
+        "method", stats.queries_received_by_method.iter(),
+    );
+
+    write_labeled(
+        &mut out, "counter",
+        "kademliar_errors_total", "Error responses received, by code.",
+        "code", stats.errors_by_code.iter(),
+    );
+
+    write_counter(&mut out, "kademliar_responses_total", "Responses received.", s.responses);
+    write_counter(&mut out, "kademliar_timeouts_total", "Queries that timed out.", s.timeouts);
+    write_counter(&mut out, "kademliar_retries_total", "Queries retried after a timeout.", s.retries);
+    write_counter(&mut out, "kademliar_send_errors_total", "Datagrams that failed to send.", s.send_errors);
+    write_counter(&mut out, "kademliar_replies_sent_total", "Replies sent to queries.", s.replies_sent);
+
+    write_labeled(
+        &mut out, "counter",
+        "kademliar_dropped_total", "Inbound datagrams dropped, by reason.",
+        "reason", [
+            ("rate_limited", s.dropped_rate_limited),
+            ("blocked", s.dropped_blocked),
+            ("blocklisted", s.dropped_blocklisted),
+        ].into_iter(),
+    );
+
+    let buckets: Vec<(String, u64)> = table
+        .buckets()
+        .map(|(index, nodes)| (index.to_string(), nodes.len() as u64))
+        .collect();
+
+    write_labeled(
+        &mut out, "gauge",
+        "kademliar_routing_table_nodes", "Nodes in the routing table, by non-empty bucket.",
+        "bucket", buckets.iter().map(|(index, len)| (index.as_str(), *len)),
+    );
+
+    let _ = writeln!(out, "# HELP kademliar_requests_in_flight Queries waiting for an answer.");
+    let _ = writeln!(out, "# TYPE kademliar_requests_in_flight gauge");
+    let _ = writeln!(out, "kademliar_requests_in_flight {}", in_flight);
+
+    stats.rtt.write(&mut out, "kademliar_rtt_seconds", "Time from sending a query to its answer.");
+    stats.lookup_duration.write(&mut out, "kademliar_lookup_duration_seconds", "Duration of lookups.");
+
+    out
+}
+
+/// How long to wait after a failed accept, e.g. when we're out of file
+/// descriptors, before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves `GET /metrics` on `addr` in the background.
+pub async fn spawn(addr: &str, dht: Dht) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics server: {}", addr))?;
+
+    info!(addr = ?listener.local_addr(), "metrics: listening");
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!(err = ?e, "metrics: accept failed");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                },
+            };
+
+            let dht = dht.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &dht).await {
+                    debug!(err = ?e, "metrics: connection failed");
+                }
+            });
+        }
+    }))
+}
+
+/// Just enough HTTP/1.x for scrapers: one request per connection.
+async fn handle_connection(mut stream: TcpStream, dht: &Dht) -> Result<()> {
+    let mut buf = vec![0; 4096];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => {
+            let body = render(
+                dht.krpc_stats(),
+                &dht.routing_table().await,
+                dht.in_flight().await.len(),
+            );
+
+            ("200 OK", body)
+        },
+
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unknown_labels_as_other() {
+        let counter = MethodCounter::default();
+
+        counter.add("ping");
+        counter.add("ping");
+        counter.add("vote");
+
+        assert_eq!(counter.get("ping"), 2);
+        assert_eq!(counter.get(OTHER), 1);
+    }
+
+    #[test]
+    fn renders_cumulative_histogram() {
+        let stats = KrpcStats::default();
+
+        stats.rtt.observe(Duration::from_millis(5));
+        stats.rtt.observe(Duration::from_millis(40));
+        stats.rtt.observe(Duration::from_secs(60));
+        stats.errors_by_code.add(203);
+
+        let out = render(&stats, &RoutingTable::new(NodeId::random(ID_LEN_BYTES), 8), 2);
+
+        assert!(out.contains("kademliar_rtt_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(out.contains("kademliar_rtt_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("kademliar_rtt_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("kademliar_rtt_seconds_sum 60.045\n"));
+        assert!(out.contains("kademliar_errors_total{code=\"203\"} 1\n"));
+        assert!(out.contains("kademliar_requests_in_flight 2\n"));
+    }
+}
@@ -0,0 +1,569 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dht::*;
+use crate::krpc::*;
+use crate::routing::*;
+
+/// Format of the routing table printed at the end of a run. The JSON
+/// schemas are documented in the README and only ever get new fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable, not meant to be parsed.
+    #[default]
+    Text,
+
+    /// A single JSON document with every bucket.
+    Json,
+
+    /// One JSON object per node and line.
+    Ndjson,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoutingTableJson {
+    /// Our own id, in hex.
+    pub id: String,
+    pub k: usize,
+    pub buckets: Vec<BucketJson>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BucketJson {
+    pub index: usize,
+    pub nodes: Vec<NodeJson>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeJson {
+    /// Only set in NDJSON lines, where the bucket isn't implied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<usize>,
+
+    /// Node id, in hex.
+    pub id: String,
+
+    /// In ipv4:port format.
+    pub addr: String,
+
+    /// XOR distance to our own id, in hex.
+    pub distance: String,
+
+    /// Longest common prefix with our own id, in bits.
+    pub lcp: usize,
+
+    /// Unix timestamp in seconds of the last response, if any.
+    pub last_seen: Option<u64>,
+    pub failed_queries: u32,
+
+    /// One of `good`, `questionable` or `bad`.
+    pub status: &'static str,
+}
+
+impl NodeJson {
+    pub fn new(table: &RoutingTable, node: &Node, now: SystemTime) -> Self {
+        let distance = node.id.distance_to(table.id());
+        let liveness = table.liveness(&node.id);
+
+        Self {
+            bucket: None,
+            id: node.id.to_string(),
+            addr: node.addr.to_string(),
+            distance: distance.to_string(),
+            lcp: distance.lcp(),
+            last_seen: liveness
+                .last_seen
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs()),
+            failed_queries: liveness.failed_queries,
+            status: liveness.status(now).as_str(),
+        }
+    }
+}
+
+impl RoutingTableJson {
+    pub fn new(table: &RoutingTable, now: SystemTime) -> Self {
+        Self {
+            id: table.id().to_string(),
+            k: table.k(),
+            buckets: table
+                .buckets()
+                .map(|(index, nodes)| BucketJson {
+                    index,
+                    nodes: nodes.iter().map(|x| NodeJson::new(table, x, now)).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn write_routing_table(
+    w: &mut impl Write,
+    table: &RoutingTable,
+    format: OutputFormat,
+) -> Result<()> {
+    let now = SystemTime::now();
+
+    match format {
+        OutputFormat::Text => {
+            for (index, bucket) in table.buckets() {
+                writeln!(w, "k-bucket {}: {:#?}", index, bucket)?;
+            }
+        },
+
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *w, &RoutingTableJson::new(table, now))?;
+            writeln!(w)?;
+        },
+
+        OutputFormat::Ndjson => {
+            for (index, bucket) in table.buckets() {
+                for node in bucket.iter() {
+                    let line = NodeJson {
+                        bucket: Some(index),
+                        ..NodeJson::new(table, node, now)
+                    };
+
+                    serde_json::to_writer(&mut *w, &line)?;
+                    writeln!(w)?;
+                }
+            }
+        },
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// Human readable form of a command's result.
+pub trait TextOutput {
+    fn write_text(&self, w: &mut dyn Write) -> std::io::Result<()>;
+}
+
+/// Writes the result of a one-off command. NDJSON is the JSON document on a
+/// single line.
+pub fn write_result<T>(w: &mut impl Write, value: &T, format: OutputFormat) -> Result<()>
+where
+    T: Serialize + TextOutput,
+{
+    match format {
+        OutputFormat::Text => value.write_text(w)?,
+        OutputFormat::Json => serde_json::to_writer_pretty(&mut *w, value)?,
+        OutputFormat::Ndjson => serde_json::to_writer(&mut *w, value)?,
+    }
+
+    if format != OutputFormat::Text {
+        writeln!(w)?;
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// A node found by a lookup, relative to the lookup's target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FoundNodeJson {
+    /// Node id, in hex.
+    pub id: String,
+
+    /// In ipv4:port format.
+    pub addr: String,
+
+    /// XOR distance to the target, in hex.
+    pub distance: String,
+
+    /// Longest common prefix with the target, in bits.
+    pub lcp: usize,
+}
+
+impl FoundNodeJson {
+    pub fn new(node: &Node, target: &NodeId) -> Self {
+        let distance = node.id.distance_to(target);
+
+        Self {
+            id: node.id.to_string(),
+            addr: node.addr.to_string(),
+            distance: distance.to_string(),
+            lcp: distance.lcp(),
+        }
+    }
+
+    fn write_text(nodes: &[Self], w: &mut dyn Write) -> std::io::Result<()> {
+        for node in nodes.iter() {
+            writeln!(w, "{} {} lcp={}", node.id, node.addr, node.lcp)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PingJson {
+    pub id: String,
+    pub addr: String,
+}
+
+impl PingJson {
+    pub fn new(id: &NodeId, addr: &NodeAddr) -> Self {
+        Self { id: id.to_string(), addr: addr.to_string() }
+    }
+}
+
+impl TextOutput for PingJson {
+    fn write_text(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(w, "{} {}", self.id, self.addr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LookupJson {
+    pub target: String,
+
+    /// Closest first.
+    pub nodes: Vec<FoundNodeJson>,
+}
+
+impl LookupJson {
+    pub fn new(target: &NodeId, nodes: &[Node]) -> Self {
+        Self {
+            target: target.to_string(),
+            nodes: nodes.iter().map(|x| FoundNodeJson::new(x, target)).collect(),
+        }
+    }
+}
+
+impl TextOutput for LookupJson {
+    fn write_text(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        FoundNodeJson::write_text(&self.nodes, w)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GetPeersJson {
+    pub info_hash: String,
+
+    /// In ipv4:port format.
+    pub peers: Vec<String>,
+
+    /// Closest nodes that were asked for peers, closest first.
+    pub nodes: Vec<FoundNodeJson>,
+}
+
+impl GetPeersJson {
+    pub fn new(info_hash: &NodeId, result: &GetPeersResult) -> Self {
+        Self {
+            info_hash: info_hash.to_string(),
+            peers: result.peers.iter().map(|x| x.to_string()).collect(),
+            nodes: result.nodes.iter().map(|x| FoundNodeJson::new(x, info_hash)).collect(),
+        }
+    }
+}
+
+impl TextOutput for GetPeersJson {
+    fn write_text(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        for peer in self.peers.iter() {
+            writeln!(w, "peer {}", peer)?;
+        }
+
+        for node in self.nodes.iter() {
+            writeln!(w, "node {} {}", node.id, node.addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AnnounceJson {
+    pub info_hash: String,
+    pub port: Option<u16>,
+
+    /// Nodes that accepted the announcement.
+    pub nodes: Vec<FoundNodeJson>,
+}
+
+impl AnnounceJson {
+    pub fn new(info_hash: &NodeId, port: Option<u16>, nodes: &[Node]) -> Self {
+        Self {
+            info_hash: info_hash.to_string(),
+            port,
+            nodes: nodes.iter().map(|x| FoundNodeJson::new(x, info_hash)).collect(),
+        }
+    }
+}
+
+impl TextOutput for AnnounceJson {
+    fn write_text(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        FoundNodeJson::write_text(&self.nodes, w)
+    }
+}
+
+/// A query waiting for an answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RequestJson {
+    /// Transaction id, in hex.
+    pub tx_id: String,
+
+    /// In ipv4:port format.
+    pub dst: String,
+    pub method: &'static str,
+    pub attempt: u32,
+}
+
+impl RequestJson {
+    pub fn new(req: &InFlightRequest) -> Self {
+        Self {
+            tx_id: hex::encode(req.tx_id.as_slice()),
+            dst: req.dst.to_string(),
+            method: req.method,
+            attempt: req.attempt,
+        }
+    }
+}
+
+/// Format of the nodes streamed while a run is going.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryFormat {
+    /// One JSON object per node and line.
+    #[default]
+    Ndjson,
+
+    /// Comma separated values, with a header line.
+    Csv,
+}
+
+const DISCOVERY_CSV_HEADER: &str = "id,addr,referrer_id,referrer_addr,discovered_at_ms";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveredNodeJson {
+    /// Node id, in hex.
+    pub id: String,
+
+    /// In ipv4:port format.
+    pub addr: String,
+
+    /// Id of the node whose response contained this one, in hex. Not set
+    /// when we didn't know it, e.g. for bootstrap nodes that sent none.
+    pub referrer_id: Option<String>,
+    pub referrer_addr: String,
+
+    /// Unix timestamp in milliseconds.
+    pub discovered_at_ms: u64,
+}
+
+/// Writes every node the first time it's discovered, one line each, and
+/// flushes after every line so the output can be piped into other tools.
+pub struct DiscoveryWriter {
+    out: Box<dyn Write + Send>,
+    format: DiscoveryFormat,
+    seen: HashSet<NodeId>,
+}
+
+impl std::fmt::Debug for DiscoveryWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiscoveryWriter")
+            .field("format", &self.format)
+            .field("seen", &self.seen.len())
+            .finish()
+    }
+}
+
+impl DiscoveryWriter {
+    pub fn new(out: Box<dyn Write + Send>, format: DiscoveryFormat) -> Result<Self> {
+        let mut writer = Self {
+            out,
+            format,
+            seen: HashSet::new(),
+        };
+
+        if format == DiscoveryFormat::Csv {
+            writeln!(writer.out, "{}", DISCOVERY_CSV_HEADER)?;
+            writer.out.flush()?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Writes to the file at `path`, or to stdout if it's `-`.
+    pub fn create(path: &Path, format: DiscoveryFormat) -> Result<Self> {
+        if path == Path::new("-") {
+            return Self::new(Box::new(std::io::stdout()), format);
+        }
+
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create: {:?}", path))?;
+
+        Self::new(Box::new(file), format)
+    }
+
+    /// Writes `node` unless it was written before. Returns whether it was
+    /// written.
+    pub fn write(
+        &mut self,
+        node: &Node,
+        referrer_id: Option<&NodeId>,
+        referrer_addr: &NodeAddr,
+        at: SystemTime,
+    ) -> Result<bool> {
+        if !self.seen.insert(node.id.clone()) {
+            return Ok(false);
+        }
+
+        let line = DiscoveredNodeJson {
+            id: node.id.to_string(),
+            addr: node.addr.to_string(),
+            referrer_id: referrer_id.map(|x| x.to_string()),
+            referrer_addr: referrer_addr.to_string(),
+            discovered_at_ms: at
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_millis() as u64)
+                .unwrap_or_default(),
+        };
+
+        match self.format {
+            DiscoveryFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, &line)?;
+                writeln!(self.out)?;
+            },
+
+            DiscoveryFormat::Csv => {
+                writeln!(
+                    self.out,
+                    "{},{},{},{},{}",
+                    line.id,
+                    line.addr,
+                    line.referrer_id.unwrap_or_default(),
+                    line.referrer_addr,
+                    line.discovered_at_ms
+                )?;
+            },
+        }
+
+        self.out.flush()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn table() -> RoutingTable {
+        let mut table = RoutingTable::new(
+            NodeId::from_hex("0000000000000000000000000000000000000000").unwrap(),
+            8,
+        );
+
+        table.insert(Node {
+            id: NodeId::from_hex("1000000000000000000000000000000000000001").unwrap(),
+            addr: NodeAddr::from_str("1.2.3.4:6881").unwrap(),
+        });
+
+        table
+    }
+
+    #[test]
+    fn writes_ndjson_line_per_node() -> Result<()> {
+        let mut out = vec![];
+
+        write_routing_table(&mut out, &table(), OutputFormat::Ndjson)?;
+
+        assert_eq!(
+            String::from_utf8(out)?,
+            concat!(
+                r#"{"bucket":0,"id":"1000000000000000000000000000000000000001","#,
+                r#""addr":"1.2.3.4:6881","distance":"1000000000000000000000000000000000000001","#,
+                r#""lcp":3,"last_seen":null,"failed_queries":0,"status":"questionable"}"#,
+                "\n",
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_json_document_with_buckets() -> Result<()> {
+        let mut out = vec![];
+
+        write_routing_table(&mut out, &table(), OutputFormat::Json)?;
+
+        let value: serde_json::Value = serde_json::from_slice(&out)?;
+
+        assert_eq!(value["k"], 8);
+        assert_eq!(value["buckets"][0]["index"], 0);
+        assert_eq!(value["buckets"][0]["nodes"][0]["addr"], "1.2.3.4:6881");
+        assert!(value["buckets"][0]["nodes"][0].get("bucket").is_none());
+
+        Ok(())
+    }
+
+    /// Hands out what was written so far.
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_each_discovered_node_once_as_csv() -> Result<()> {
+        let buf = SharedBuf::default();
+        let mut writer = DiscoveryWriter::new(Box::new(buf.clone()), DiscoveryFormat::Csv)?;
+
+        let node = Node {
+            id: NodeId::from_hex("1000000000000000000000000000000000000001")?,
+            addr: NodeAddr::from_str("1.2.3.4:6881")?,
+        };
+
+        let referrer = NodeAddr::from_str("5.6.7.8:6881")?;
+        let at = UNIX_EPOCH + std::time::Duration::from_millis(1_500);
+
+        assert!(writer.write(&node, None, &referrer, at)?);
+        assert!(!writer.write(&node, None, &referrer, at)?);
+
+        assert_eq!(
+            String::from_utf8(buf.0.lock().unwrap().clone())?,
+            concat!(
+                "id,addr,referrer_id,referrer_addr,discovered_at_ms\n",
+                "1000000000000000000000000000000000000001,1.2.3.4:6881,,5.6.7.8:6881,1500\n",
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_command_results_in_every_format() -> Result<()> {
+        let result = PingJson {
+            id: "1000000000000000000000000000000000000001".into(),
+            addr: "1.2.3.4:6881".into(),
+        };
+
+        let mut out = vec![];
+        write_result(&mut out, &result, OutputFormat::Text)?;
+        assert_eq!(String::from_utf8(out)?, "1000000000000000000000000000000000000001 1.2.3.4:6881\n");
+
+        let mut out = vec![];
+        write_result(&mut out, &result, OutputFormat::Ndjson)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "{\"id\":\"1000000000000000000000000000000000000001\",\"addr\":\"1.2.3.4:6881\"}\n"
+        );
+
+        Ok(())
+    }
+}
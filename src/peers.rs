@@ -0,0 +1,243 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+use crate::krpc::*;
+use crate::ratelimit::*;
+
+/// How long an announced peer is kept without being announced again.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often the token secret changes. Tokens from the previous secret are
+/// still accepted, so a token is valid for up to twice as long (BEP 5).
+pub const TOKEN_ROTATE_EVERY: Duration = Duration::from_secs(5 * 60);
+
+/// Most peers returned for a single `get_peers` query, so the answer fits
+/// into a single datagram.
+pub const MAX_PEERS_PER_REPLY: usize = 50;
+
+/// Caps on what other nodes can make us store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLimit {
+    /// Announcements for new info hashes are ignored once this many are
+    /// stored.
+    pub max_info_hashes: usize,
+
+    /// A new peer replaces the one announced longest ago once an info hash
+    /// has this many.
+    pub max_peers_per_info_hash: usize,
+}
+
+impl Default for PeerLimit {
+    fn default() -> Self {
+        Self {
+            max_info_hashes: 10_000,
+            max_peers_per_info_hash: 1_000,
+        }
+    }
+}
+
+/// Tokens handed out with `get_peers` answers, which have to come back with
+/// `announce_peer` from the same IP.
+#[derive(Debug)]
+pub struct Tokens {
+    current: RandomState,
+    previous: RandomState,
+    rotated_at: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(TokioClock))
+    }
+}
+
+impl Tokens {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            current: RandomState::new(),
+            previous: RandomState::new(),
+            rotated_at: clock.now(),
+            clock,
+        }
+    }
+
+    pub fn issue(&mut self, ip: &Ipv4Addr) -> Vec<u8> {
+        self.rotate_if_due();
+        Self::token(&self.current, ip)
+    }
+
+    pub fn verify(&mut self, ip: &Ipv4Addr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+
+        token == Self::token(&self.current, ip)
+            || token == Self::token(&self.previous, ip)
+    }
+
+    fn rotate_if_due(&mut self) {
+        let now = self.clock.now();
+
+        while now.saturating_duration_since(self.rotated_at) >= TOKEN_ROTATE_EVERY {
+            self.previous = std::mem::replace(&mut self.current, RandomState::new());
+            self.rotated_at += TOKEN_ROTATE_EVERY;
+        }
+    }
+
+    fn token(secret: &RandomState, ip: &Ipv4Addr) -> Vec<u8> {
+        secret.hash_one(ip).to_be_bytes().to_vec()
+    }
+}
+
+/// Peers announced to us, by info hash.
+#[derive(Debug)]
+pub struct PeerStore {
+    peers: HashMap<NodeId, HashMap<NodeAddr, Instant>>,
+    limit: PeerLimit,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(TokioClock))
+    }
+}
+
+impl PeerStore {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { peers: HashMap::new(), limit: PeerLimit::default(), clock }
+    }
+
+    pub fn with_limit(mut self, limit: PeerLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Number of info hashes with at least one peer.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn announce(&mut self, info_hash: NodeId, peer: NodeAddr) {
+        let now = self.clock.now();
+
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= self.limit.max_info_hashes {
+            self.expire();
+
+            if self.peers.len() >= self.limit.max_info_hashes {
+                return;
+            }
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+
+        if !peers.contains_key(&peer) && peers.len() >= self.limit.max_peers_per_info_hash {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(x, _)| x.clone());
+
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+
+        peers.insert(peer, now);
+    }
+
+    /// Up to [`MAX_PEERS_PER_REPLY`] peers that haven't expired yet.
+    pub fn peers(&mut self, info_hash: &NodeId) -> Vec<NodeAddr> {
+        self.expire();
+
+        self.peers
+            .get(info_hash)
+            .map(|x| x.keys().take(MAX_PEERS_PER_REPLY).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn expire(&mut self) {
+        let now = self.clock.now();
+
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, at| now.saturating_duration_since(*at) < PEER_TTL);
+        }
+
+        self.peers.retain(|_, x| !x.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn accepts_tokens_from_current_and_previous_secret() {
+        let clock = Arc::new(FakeClock::default());
+        let mut tokens = Tokens::with_clock(clock.clone());
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        let token = tokens.issue(&ip);
+
+        assert!(tokens.verify(&ip, &token));
+        assert!(!tokens.verify(&Ipv4Addr::new(1, 2, 3, 5), &token));
+
+        clock.advance(TOKEN_ROTATE_EVERY);
+        assert!(tokens.verify(&ip, &token));
+
+        clock.advance(TOKEN_ROTATE_EVERY);
+        assert!(!tokens.verify(&ip, &token));
+    }
+
+    #[test]
+    fn expires_peers() -> anyhow::Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let mut store = PeerStore::with_clock(clock.clone());
+        let info_hash = NodeId::from_hex("35a35935f5226f7a6adcb84aa4da1b62c71023e1")?;
+        let peer = NodeAddr::from_str("1.2.3.4:6881")?;
+
+        store.announce(info_hash.clone(), peer.clone());
+        assert_eq!(store.peers(&info_hash), vec![peer]);
+
+        clock.advance(PEER_TTL);
+        assert_eq!(store.peers(&info_hash), vec![]);
+        assert!(store.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits_stored_peers() -> anyhow::Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let mut store = PeerStore::with_clock(clock.clone()).with_limit(PeerLimit {
+            max_info_hashes: 1,
+            max_peers_per_info_hash: 2,
+        });
+
+        let info_hash = NodeId::from_hex("35a35935f5226f7a6adcb84aa4da1b62c71023e1")?;
+        let peers: Vec<NodeAddr> = (1..=3)
+            .map(|x| NodeAddr::from_str(&format!("1.2.3.4:{}", x)))
+            .collect::<Result<_, _>>()?;
+
+        for peer in peers.iter() {
+            store.announce(info_hash.clone(), peer.clone());
+            clock.advance(Duration::from_secs(1));
+        }
+
+        let mut stored = store.peers(&info_hash);
+        stored.sort_by_key(|x| x.port);
+        assert_eq!(stored, peers[1..]);
+
+        let other = NodeId::from_hex("0000000000000000000000000000000000000001")?;
+        store.announce(other.clone(), peers[0].clone());
+        assert_eq!(store.peers(&other), vec![]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+/// Source of the current time, so the rate limiter can be tested without
+/// waiting.
+pub trait Clock: std::fmt::Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// Tokio's clock. Follows `tokio::time::pause`, so tests with
+/// `start_paused = true` can use it as well.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug)]
+pub struct FakeClock(Mutex<Instant>);
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+}
+
+impl FakeClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Upper bounds for outgoing traffic. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>,
+}
+
+/// Holds up to one second worth of tokens, refilled continuously.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    /// How long until `cost` tokens are available. A cost larger than the
+    /// bucket only has to wait for a full bucket, and leaves it in debt.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.rate) - self.tokens;
+
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+/// Token buckets for packets and bytes per second. A packet is only let
+/// through once both buckets allow it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimit::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self::with_clock(limit, Arc::new(TokioClock))
+    }
+
+    pub fn with_clock(limit: RateLimit, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+
+        Self {
+            packets: limit.packets_per_sec.map(|x| TokenBucket::new(x, now)),
+            bytes: limit.bytes_per_sec.map(|x| TokenBucket::new(x, now)),
+            clock,
+        }
+    }
+
+    /// Takes the tokens for a packet of `len` bytes, or returns how long to
+    /// wait before trying again. Takes nothing in the latter case.
+    pub fn try_acquire(&mut self, len: usize) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut wait = Duration::ZERO;
+
+        for (bucket, cost) in [(&mut self.packets, 1.0), (&mut self.bytes, len as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                wait = wait.max(bucket.wait_time(cost));
+            }
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for (bucket, cost) in [(&mut self.packets, 1.0), (&mut self.bytes, len as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= cost;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until a packet of `len` bytes may be sent.
+    pub async fn acquire(&mut self, len: usize) {
+        while let Err(wait) = self.try_acquire(len) {
+            debug!(?len, ?wait, "rate limit: wait");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits for packets received from a single IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundLimit {
+    pub packets_per_sec: u32,
+
+    /// How many sources to keep track of. The least recently seen ones are
+    /// forgotten first.
+    pub max_sources: usize,
+
+    /// How many packets in a row a source may go over the limit before it's
+    /// blocked.
+    pub block_after: u32,
+
+    pub block_for: Duration,
+}
+
+impl Default for InboundLimit {
+    fn default() -> Self {
+        Self {
+            packets_per_sec: 50,
+            max_sources: 10_000,
+            block_after: 100,
+            block_for: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundVerdict {
+    Allow,
+
+    /// Over the limit.
+    Drop,
+
+    /// The source just got blocked, for this long.
+    Block(Duration),
+
+    /// The source is still blocked.
+    Blocked,
+}
+
+#[derive(Debug)]
+struct Source {
+    bucket: TokenBucket,
+    strikes: u32,
+    blocked_until: Option<Instant>,
+}
+
+/// Token bucket per source IP address, checked before a packet is decoded.
+#[derive(Debug)]
+pub struct InboundRateLimiter {
+    limit: InboundLimit,
+    sources: LruCache<IpAddr, Source>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for InboundRateLimiter {
+    fn default() -> Self {
+        Self::new(InboundLimit::default())
+    }
+}
+
+impl InboundRateLimiter {
+    pub fn new(limit: InboundLimit) -> Self {
+        Self::with_clock(limit, Arc::new(TokioClock))
+    }
+
+    pub fn with_clock(limit: InboundLimit, clock: Arc<dyn Clock>) -> Self {
+        let capacity = NonZeroUsize::new(limit.max_sources).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            limit,
+            sources: LruCache::new(capacity),
+            clock,
+        }
+    }
+
+    pub fn check(&mut self, ip: IpAddr) -> InboundVerdict {
+        let now = self.clock.now();
+        let limit = self.limit;
+
+        let source = self.sources.get_or_insert_mut(ip, || Source {
+            bucket: TokenBucket::new(limit.packets_per_sec, now),
+            strikes: 0,
+            blocked_until: None,
+        });
+
+        if let Some(until) = source.blocked_until {
+            if now < until {
+                return InboundVerdict::Blocked;
+            }
+
+            source.blocked_until = None;
+        }
+
+        source.bucket.refill(now);
+
+        if source.bucket.wait_time(1.0).is_zero() {
+            source.bucket.tokens -= 1.0;
+            source.strikes = 0;
+            return InboundVerdict::Allow;
+        }
+
+        source.strikes += 1;
+
+        if source.strikes < limit.block_after {
+            return InboundVerdict::Drop;
+        }
+
+        source.strikes = 0;
+        source.blocked_until = Some(now + limit.block_for);
+
+        InboundVerdict::Block(limit.block_for)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_packets_per_second() {
+        let clock = Arc::new(FakeClock::default());
+        let mut limiter = RateLimiter::with_clock(
+            RateLimit { packets_per_sec: Some(2), bytes_per_sec: None },
+            clock.clone(),
+        );
+
+        assert_eq!(limiter.try_acquire(100), Ok(()));
+        assert_eq!(limiter.try_acquire(100), Ok(()));
+        assert_eq!(limiter.try_acquire(100), Err(Duration::from_millis(500)));
+
+        clock.advance(Duration::from_millis(500));
+
+        assert_eq!(limiter.try_acquire(100), Ok(()));
+        assert!(limiter.try_acquire(100).is_err());
+    }
+
+    #[test]
+    fn limits_bytes_per_second() {
+        let clock = Arc::new(FakeClock::default());
+        let mut limiter = RateLimiter::with_clock(
+            RateLimit { packets_per_sec: Some(100), bytes_per_sec: Some(1_000) },
+            clock.clone(),
+        );
+
+        assert_eq!(limiter.try_acquire(600), Ok(()));
+        assert_eq!(limiter.try_acquire(600), Err(Duration::from_millis(200)));
+
+        clock.advance(Duration::from_millis(200));
+
+        assert_eq!(limiter.try_acquire(600), Ok(()));
+    }
+
+    #[test]
+    fn lets_oversized_packets_through_once_bucket_is_full() {
+        let clock = Arc::new(FakeClock::default());
+        let mut limiter = RateLimiter::with_clock(
+            RateLimit { packets_per_sec: None, bytes_per_sec: Some(1_000) },
+            clock.clone(),
+        );
+
+        assert_eq!(limiter.try_acquire(1_500), Ok(()));
+
+        // In debt by 500 bytes, so a full bucket takes 1.5 seconds.
+        assert_eq!(limiter.try_acquire(1_500), Err(Duration::from_millis(1_500)));
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let mut limiter = RateLimiter::default();
+
+        for _ in 0..10_000 {
+            assert_eq!(limiter.try_acquire(10_000), Ok(()));
+        }
+    }
+
+    fn inbound_limiter(clock: Arc<FakeClock>, max_sources: usize) -> InboundRateLimiter {
+        InboundRateLimiter::with_clock(
+            InboundLimit {
+                packets_per_sec: 2,
+                max_sources,
+                block_after: 3,
+                block_for: Duration::from_secs(60),
+            },
+            clock,
+        )
+    }
+
+    #[test]
+    fn drops_and_then_blocks_flooding_source() {
+        let clock = Arc::new(FakeClock::default());
+        let mut limiter = inbound_limiter(clock.clone(), 16);
+        let flooder: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert_eq!(limiter.check(flooder), InboundVerdict::Allow);
+        assert_eq!(limiter.check(flooder), InboundVerdict::Allow);
+        assert_eq!(limiter.check(flooder), InboundVerdict::Drop);
+        assert_eq!(limiter.check(flooder), InboundVerdict::Drop);
+        assert_eq!(limiter.check(flooder), InboundVerdict::Block(Duration::from_secs(60)));
+
+        // Other sources are unaffected.
+        assert_eq!(limiter.check(other), InboundVerdict::Allow);
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(limiter.check(flooder), InboundVerdict::Blocked);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.check(flooder), InboundVerdict::Allow);
+    }
+
+    #[test]
+    fn forgets_least_recently_seen_sources() {
+        let clock = Arc::new(FakeClock::default());
+        let mut limiter = inbound_limiter(clock, 2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+
+        limiter.check(a);
+        limiter.check(a);
+        assert_eq!(limiter.check(a), InboundVerdict::Drop);
+
+        limiter.check("10.0.0.2".parse().unwrap());
+        limiter.check("10.0.0.3".parse().unwrap());
+
+        // Evicted, so it starts over with a full bucket.
+        assert_eq!(limiter.sources.len(), 2);
+        assert_eq!(limiter.check(a), InboundVerdict::Allow);
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::blocklist::*;
+use crate::events::*;
+use crate::krpc::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertResult {
+    Added,
+    AlreadyPresent,
+    BucketFull,
+    IsSelf,
+    Blocked,
+}
+
+/// How long a node counts as good after it last responded (BEP 5).
+pub const NODE_GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+
+/// Failed queries in a row after which a node counts as bad.
+pub const NODE_BAD_AFTER_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Good,
+    Questionable,
+    Bad,
+}
+
+impl NodeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Good => "good",
+            Self::Questionable => "questionable",
+            Self::Bad => "bad",
+        }
+    }
+}
+
+/// What we know about whether a node is still around.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Liveness {
+    /// When the node last responded to one of our queries.
+    pub last_seen: Option<SystemTime>,
+
+    /// Queries in a row that the node didn't respond to.
+    pub failed_queries: u32,
+}
+
+impl Liveness {
+    pub fn status(&self, now: SystemTime) -> NodeStatus {
+        if self.failed_queries >= NODE_BAD_AFTER_FAILURES {
+            return NodeStatus::Bad;
+        }
+
+        match self.last_seen.and_then(|x| now.duration_since(x).ok()) {
+            Some(elapsed) if elapsed < NODE_GOOD_FOR => NodeStatus::Good,
+            _ => NodeStatus::Questionable,
+        }
+    }
+}
+
+/// K-buckets of the nodes we know about. Bucket `i` holds the nodes whose
+/// distance to our own id has a longest common prefix of exactly `i`, except
+/// for the last bucket, which holds everything from its index up. Once the
+/// last bucket fills up, it is split in two.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    k: usize,
+    buckets: Vec<Vec<Node>>,
+    liveness: HashMap<NodeId, Liveness>,
+    events: Option<EventSender>,
+    blocklist: Option<Arc<Blocklist>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId, k: usize) -> Self {
+        Self {
+            id,
+            k,
+            buckets: vec![vec![]],
+            liveness: HashMap::new(),
+            events: None,
+            blocklist: None,
+        }
+    }
+
+    /// Emits changes to the table through `events`.
+    pub fn with_events(mut self, events: EventSender) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Refuses nodes on `blocklist`, and leaves out the ones that got on it
+    /// after they were added when looking up the closest nodes.
+    pub fn with_blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|x| x.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|x| x.is_empty())
+    }
+
+    pub fn bucket_index(&self, id: &NodeId) -> usize {
+        id
+            .distance_to(&self.id)
+            .lcp()
+            .min(self.buckets.len() - 1)
+    }
+
+    /// Non-empty buckets, in order of their index.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, &[Node])> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !x.is_empty())
+            .map(|(index, x)| (index, x.as_slice()))
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    /// Ids of the nodes at `addr`.
+    pub fn ids_at(&self, addr: &NodeAddr) -> Vec<NodeId> {
+        self.nodes()
+            .filter(|x| x.addr == *addr)
+            .map(|x| x.id.clone())
+            .collect()
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.buckets[self.bucket_index(id)]
+            .iter()
+            .any(|x| x.id == *id)
+    }
+
+    pub fn liveness(&self, id: &NodeId) -> Liveness {
+        self.liveness.get(id).copied().unwrap_or_default()
+    }
+
+    /// Records a response from the node, if it's in the table.
+    pub fn mark_seen(&mut self, id: &NodeId, at: SystemTime) {
+        if self.contains(id) {
+            self.liveness.insert(id.clone(), Liveness {
+                last_seen: Some(at),
+                failed_queries: 0,
+            });
+        }
+    }
+
+    /// Records a query the node didn't respond to, if it's in the table.
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        if self.contains(id) {
+            self.liveness.entry(id.clone()).or_default().failed_queries += 1;
+        }
+    }
+
+    pub fn insert(&mut self, node: Node) -> InsertResult {
+        if node.id == self.id {
+            return InsertResult::IsSelf;
+        }
+
+        if self.is_blocked(&node) {
+            return InsertResult::Blocked;
+        }
+
+        loop {
+            let index = self.bucket_index(&node.id);
+            let is_last = index == self.buckets.len() - 1;
+            let bucket = &mut self.buckets[index];
+
+            if bucket.iter().any(|x| x.id == node.id) {
+                return InsertResult::AlreadyPresent;
+            }
+
+            if bucket.len() < self.k {
+                bucket.push(node.clone());
+                self.emit(DhtEvent::NodeAdded { node, bucket: index });
+                return InsertResult::Added;
+            }
+
+            if !is_last || index >= ID_LEN_BITS - 1 {
+                return InsertResult::BucketFull;
+            }
+
+            self.split_last_bucket();
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> Option<Node> {
+        let (bucket, node) = self.take(id)?;
+
+        self.emit(DhtEvent::NodeRemoved { node: node.clone(), bucket });
+        Some(node)
+    }
+
+    /// Same as [`RoutingTable::remove`], for nodes that stopped responding.
+    pub fn evict(&mut self, id: &NodeId) -> Option<Node> {
+        let (bucket, node) = self.take(id)?;
+
+        self.emit(DhtEvent::NodeEvicted { node: node.clone(), bucket });
+        Some(node)
+    }
+
+    /// Up to `count` nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .nodes()
+            .filter(|x| !self.is_blocked(x))
+            .cloned()
+            .collect();
+
+        nodes.sort_by_cached_key(|x| x.id.distance_to(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Random id that falls into bucket `index`, to look up when refreshing
+    /// the bucket.
+    pub fn random_id_in_bucket(&self, index: usize) -> NodeId {
+        self.id.random_with_lcp(index)
+    }
+
+    /// Indexes of the buckets without a single good node, including empty
+    /// ones. These are the ones worth refreshing.
+    pub fn stale_buckets(&self, now: SystemTime) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, nodes)| {
+                !nodes
+                    .iter()
+                    .any(|x| self.liveness(&x.id).status(now) == NodeStatus::Good)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Nodes that haven't responded for a while and should be pinged.
+    pub fn questionable_nodes(&self, now: SystemTime) -> Vec<Node> {
+        self.nodes()
+            .filter(|x| self.liveness(&x.id).status(now) == NodeStatus::Questionable)
+            .cloned()
+            .collect()
+    }
+
+    fn split_last_bucket(&mut self) {
+        let index = self.buckets.len() - 1;
+        let (near, far): (Vec<Node>, Vec<Node>) = self.buckets[index]
+            .drain(..)
+            .partition(|x| x.id.distance_to(&self.id).lcp() > index);
+
+        self.buckets[index] = far;
+        self.buckets.push(near);
+
+        self.emit(DhtEvent::BucketSplit { bucket: index + 1 });
+    }
+
+    fn take(&mut self, id: &NodeId) -> Option<(usize, Node)> {
+        let index = self.bucket_index(id);
+        let bucket = &mut self.buckets[index];
+        let position = bucket.iter().position(|x| x.id == *id)?;
+
+        self.liveness.remove(id);
+        Some((index, bucket.remove(position)))
+    }
+
+    fn is_blocked(&self, node: &Node) -> bool {
+        self.blocklist.as_ref().is_some_and(|x| x.blocks_node(node))
+    }
+
+    fn emit(&self, event: DhtEvent) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    fn node(id: &str, port: u16) -> Node {
+        Node {
+            id: NodeId::from_hex(id).unwrap(),
+            addr: NodeAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap(),
+        }
+    }
+
+    fn own_id() -> NodeId {
+        NodeId::from_hex("0000000000000000000000000000000000000000").unwrap()
+    }
+
+    #[test]
+    fn rejects_self_and_duplicates() {
+        let mut table = RoutingTable::new(own_id(), 2);
+
+        assert_eq!(
+            table.insert(node("0000000000000000000000000000000000000000", 1)),
+            InsertResult::IsSelf
+        );
+
+        assert_eq!(
+            table.insert(node("8000000000000000000000000000000000000000", 1)),
+            InsertResult::Added
+        );
+
+        assert_eq!(
+            table.insert(node("8000000000000000000000000000000000000000", 2)),
+            InsertResult::AlreadyPresent
+        );
+
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn splits_last_bucket_when_full() {
+        let mut table = RoutingTable::new(own_id(), 2);
+
+        table.insert(node("8000000000000000000000000000000000000000", 1));
+        table.insert(node("4000000000000000000000000000000000000000", 2));
+
+        // Bucket 0 is the only (and so the last) bucket, so it gets split.
+        assert_eq!(
+            table.insert(node("2000000000000000000000000000000000000000", 3)),
+            InsertResult::Added
+        );
+
+        let buckets: Vec<(usize, usize)> = table
+            .buckets()
+            .map(|(index, nodes)| (index, nodes.len()))
+            .collect();
+
+        assert_eq!(buckets, vec![(0, 1), (1, 2)]);
+
+        // Bucket 0 is no longer the last one, so it can't be split again.
+        assert_eq!(
+            table.insert(node("c000000000000000000000000000000000000000", 4)),
+            InsertResult::Added
+        );
+
+        assert_eq!(
+            table.insert(node("e000000000000000000000000000000000000000", 5)),
+            InsertResult::BucketFull
+        );
+    }
+
+    #[test]
+    fn returns_closest_nodes_first() {
+        let mut table = RoutingTable::new(own_id(), 8);
+
+        table.insert(node("f000000000000000000000000000000000000000", 1));
+        table.insert(node("1000000000000000000000000000000000000000", 2));
+        table.insert(node("3000000000000000000000000000000000000000", 3));
+
+        let target = NodeId::from_hex("1100000000000000000000000000000000000000").unwrap();
+        let ports: Vec<u16> = table
+            .closest(&target, 2)
+            .iter()
+            .map(|x| x.addr.port)
+            .collect();
+
+        assert_eq!(ports, vec![2, 3]);
+    }
+
+    #[test]
+    fn refuses_and_hides_blocked_nodes() -> anyhow::Result<()> {
+        let blocklist = Arc::new(Blocklist::new("ff".parse()?));
+        let mut table = RoutingTable::new(own_id(), 8).with_blocklist(blocklist);
+
+        assert_eq!(
+            table.insert(node("ff00000000000000000000000000000000000000", 1)),
+            InsertResult::Blocked
+        );
+
+        table.insert(node("1000000000000000000000000000000000000000", 2));
+
+        assert_eq!(table.closest(&own_id(), 8).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_liveness_of_nodes() {
+        let mut table = RoutingTable::new(own_id(), 8);
+        let node = node("1000000000000000000000000000000000000000", 1);
+        let now = SystemTime::now();
+
+        table.insert(node.clone());
+        assert_eq!(table.liveness(&node.id).status(now), NodeStatus::Questionable);
+
+        table.mark_seen(&node.id, now);
+        assert_eq!(table.liveness(&node.id).status(now), NodeStatus::Good);
+        assert_eq!(
+            table.liveness(&node.id).status(now + NODE_GOOD_FOR),
+            NodeStatus::Questionable
+        );
+
+        for _ in 0..NODE_BAD_AFTER_FAILURES {
+            table.mark_failed(&node.id);
+        }
+
+        assert_eq!(table.liveness(&node.id).status(now), NodeStatus::Bad);
+
+        table.remove(&node.id);
+        assert_eq!(table.liveness(&node.id), Liveness::default());
+    }
+
+    #[test]
+    fn finds_stale_buckets_and_questionable_nodes() {
+        let mut table = RoutingTable::new(own_id(), 1);
+        let good = node("8000000000000000000000000000000000000000", 1);
+        let questionable = node("4000000000000000000000000000000000000000", 2);
+        let now = SystemTime::now();
+
+        table.insert(good.clone());
+        table.insert(questionable.clone());
+        table.mark_seen(&good.id, now);
+
+        assert_eq!(table.stale_buckets(now), vec![1]);
+        assert_eq!(table.questionable_nodes(now), vec![questionable]);
+        assert_eq!(table.bucket_index(&table.random_id_in_bucket(1)), 1);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert { lcp: usize, rest: [u8; ID_LEN_BYTES], port: u16 },
+        Fail(usize),
+        Seen(usize),
+        Remove(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // Short prefixes mostly, so the first buckets fill up, but long ones
+        // too, so the last bucket keeps getting split.
+        let lcp = prop_oneof![3 => 0..8usize, 1 => 0..ID_LEN_BITS];
+
+        prop_oneof![
+            6 => (lcp, any::<[u8; ID_LEN_BYTES]>(), any::<u16>())
+                .prop_map(|(lcp, rest, port)| Op::Insert { lcp, rest, port }),
+            3 => any::<usize>().prop_map(Op::Fail),
+            1 => any::<usize>().prop_map(Op::Seen),
+            1 => any::<usize>().prop_map(Op::Remove),
+        ]
+    }
+
+    fn check_invariants(table: &RoutingTable) -> Result<(), TestCaseError> {
+        let last = table.buckets.len() - 1;
+        let mut ids = HashSet::new();
+
+        prop_assert!(table.buckets.len() <= ID_LEN_BITS);
+
+        for (index, nodes) in table.buckets() {
+            prop_assert!(nodes.len() <= table.k(), "bucket {} has {} nodes", index, nodes.len());
+
+            for node in nodes {
+                let lcp = node.id.distance_to(table.id()).lcp();
+
+                prop_assert!(ids.insert(node.id.clone()), "{} is in the table twice", node.id);
+                prop_assert!(index == lcp || (index == last && lcp > last), "{} is in bucket {}", node.id, index);
+                prop_assert_eq!(table.bucket_index(&node.id), index);
+            }
+        }
+
+        prop_assert_eq!(table.len(), ids.len());
+        prop_assert!(table.liveness.keys().all(|x| ids.contains(x)));
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn bucket_index_is_lcp_up_to_last_bucket(
+            rest in any::<[u8; ID_LEN_BYTES]>(),
+            lcp in 0..ID_LEN_BITS,
+            buckets in 1..=ID_LEN_BITS,
+        ) {
+            let mut table = RoutingTable::new(NodeId::new(rest), 8);
+            table.buckets.resize(buckets, vec![]);
+
+            let id = table.id().with_lcp(lcp, NodeId::new(rest.map(|x| !x)));
+
+            prop_assert_eq!(id.distance_to(table.id()).lcp(), lcp);
+            prop_assert_eq!(table.bucket_index(&id), lcp.min(buckets - 1));
+            prop_assert_eq!(table.bucket_index(&table.random_id_in_bucket(lcp.min(buckets - 1))), lcp.min(buckets - 1));
+        }
+
+        #[test]
+        fn keeps_invariants_under_random_operations(
+            own in any::<[u8; ID_LEN_BYTES]>(),
+            k in 1..8usize,
+            ops in proptest::collection::vec(op(), 1..200),
+        ) {
+            let own = NodeId::new(own);
+            let mut table = RoutingTable::new(own.clone(), k);
+            let now = SystemTime::now();
+
+            // Ids in the table, in the order they were added.
+            let mut model: Vec<NodeId> = vec![];
+
+            for op in ops {
+                match op {
+                    Op::Insert { lcp, rest, port } => {
+                        let node = Node {
+                            id: own.with_lcp(lcp, NodeId::new(rest)),
+                            addr: NodeAddr::new([127, 0, 0, 1].into(), port),
+                        };
+
+                        let known = model.contains(&node.id);
+
+                        match table.insert(node.clone()) {
+                            InsertResult::Added => {
+                                prop_assert!(!known);
+                                model.push(node.id);
+                            }
+                            InsertResult::AlreadyPresent => prop_assert!(known),
+                            InsertResult::BucketFull => {
+                                prop_assert!(!known);
+                                prop_assert!(table.bucket_index(&node.id) < table.buckets.len() - 1);
+                            }
+                            result => prop_assert!(false, "unexpected {:?}", result),
+                        }
+                    }
+                    Op::Fail(_) | Op::Seen(_) | Op::Remove(_) if model.is_empty() => {}
+                    Op::Fail(index) => {
+                        let id = model[index % model.len()].clone();
+                        table.mark_failed(&id);
+
+                        if table.liveness(&id).status(now) == NodeStatus::Bad {
+                            prop_assert!(table.evict(&id).is_some());
+                            model.retain(|x| *x != id);
+                        }
+                    }
+                    Op::Seen(index) => {
+                        let id = &model[index % model.len()];
+                        table.mark_seen(id, now);
+
+                        prop_assert_eq!(table.liveness(id).status(now), NodeStatus::Good);
+                    }
+                    Op::Remove(index) => {
+                        let id = model.remove(index % model.len());
+                        prop_assert!(table.remove(&id).is_some());
+                    }
+                }
+
+                check_invariants(&table)?;
+            }
+
+            let ids: HashSet<&NodeId> = table.nodes().map(|x| &x.id).collect();
+            prop_assert_eq!(ids, model.iter().collect());
+        }
+    }
+}
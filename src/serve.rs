@@ -0,0 +1,220 @@
+use anyhow::{bail, Result};
+use futures::future::join_all;
+use std::future::Future;
+use std::time::SystemTime;
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+use tracing::{debug, info};
+
+use crate::dht::*;
+use crate::krpc::*;
+
+/// Settings for staying on the network.
+#[derive(Debug, Clone)]
+pub struct ServeConfig {
+    /// How often to look for buckets without good nodes and refresh them.
+    pub refresh_every: Duration,
+
+    /// How often to ping the nodes that haven't responded for a while.
+    pub ping_every: Duration,
+
+    /// How often to announce `announce` again. Nodes forget peers after a
+    /// while, so this has to be shorter than that.
+    pub republish_every: Duration,
+
+    /// Info hashes we're a peer for.
+    pub announce: Vec<NodeId>,
+
+    /// Port to announce. The nodes use the source port if not set.
+    pub port: Option<u16>,
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        Self {
+            refresh_every: Duration::from_secs(60),
+            ping_every: Duration::from_secs(5 * 60),
+            republish_every: Duration::from_secs(15 * 60),
+            announce: vec![],
+            port: None,
+        }
+    }
+}
+
+/// Keeps the routing table of a running node fresh and its announcements
+/// alive. The node itself answers queries in the background.
+#[derive(Debug)]
+pub struct Daemon {
+    dht: Dht,
+    config: ServeConfig,
+    refresh: Interval,
+    ping: Interval,
+    republish: Interval,
+}
+
+impl Daemon {
+    /// Fails if any of the periods is zero.
+    pub fn new(dht: Dht, config: ServeConfig) -> Result<Self> {
+        let every = |name: &str, period: Duration| {
+            if period.is_zero() {
+                bail!("{} must not be zero", name);
+            }
+
+            let mut x = interval(period);
+            x.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Ok(x)
+        };
+
+        Ok(Self {
+            refresh: every("refresh period", config.refresh_every)?,
+            ping: every("ping period", config.ping_every)?,
+            republish: every("republish period", config.republish_every)?,
+            dht,
+            config,
+        })
+    }
+
+    /// Runs until `shutdown` completes, and returns its result. Work in
+    /// progress at that point is dropped.
+    pub async fn run<T>(&mut self, shutdown: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                result = &mut shutdown => {
+                    debug!("serve: exit");
+                    return result;
+                },
+
+                _ = self.tick() => {},
+            }
+        }
+    }
+
+    /// Waits for the next task that is due and runs it. All of them are due
+    /// right away on the first calls.
+    async fn tick(&mut self) {
+        tokio::select! {
+            _ = self.refresh.tick() => self.refresh_buckets().await,
+            _ = self.ping.tick() => self.ping_questionable_nodes().await,
+            _ = self.republish.tick() => self.republish().await,
+        }
+    }
+
+    /// Looks up a random id in every bucket without a good node. Bootstraps
+    /// again if the routing table ran empty.
+    pub async fn refresh_buckets(&self) {
+        let table = self.dht.routing_table().await;
+
+        if table.is_empty() {
+            match self.dht.bootstrap().await {
+                Ok(nodes) => info!(?nodes, "serve: bootstrapped"),
+                Err(e) => info!(err = ?e, "serve: bootstrap failed"),
+            }
+
+            return;
+        }
+
+        let stale = table.stale_buckets(SystemTime::now());
+
+        if stale.is_empty() {
+            return;
+        }
+
+        debug!(buckets = ?stale, "serve: refresh");
+
+        join_all(
+            stale
+                .iter()
+                .map(|x| self.dht.find_node(table.random_id_in_bucket(*x)))
+        ).await;
+
+        let nodes = self.dht.routing_table().await.len();
+
+        info!(buckets = stale.len(), nodes, "serve: refreshed");
+    }
+
+    /// Pings every questionable node. The ones that don't respond are
+    /// evicted once they're bad.
+    pub async fn ping_questionable_nodes(&self) {
+        let nodes = self
+            .dht
+            .routing_table()
+            .await
+            .questionable_nodes(SystemTime::now());
+
+        if nodes.is_empty() {
+            return;
+        }
+
+        let results = join_all(
+            nodes
+                .iter()
+                .map(|x| self.dht.ping(x.addr.clone()))
+        ).await;
+
+        info!(
+            pinged = nodes.len(),
+            responded = results.iter().filter(|x| x.is_ok()).count(),
+            "serve: pinged questionable nodes"
+        );
+    }
+
+    /// Announces every info hash in `announce` again.
+    pub async fn republish(&self) {
+        for info_hash in self.config.announce.iter() {
+            match self.dht.announce_peer(info_hash.clone(), self.config.port).await {
+                Ok(nodes) => info!(?info_hash, nodes = nodes.len(), "serve: announced"),
+                Err(e) => info!(?info_hash, err = ?e, "serve: announce failed"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_bootstrap_until_shutdown() -> Result<()> {
+        let dht = Dht::builder(DhtConfig {
+            timeout_ms: 1_000,
+            bootstrap_nodes: vec!["127.0.0.1:1000".parse()?],
+            ..Default::default()
+        })
+            .socket(KrpcSocketStub)
+            .build()?;
+
+        let mut daemon = Daemon::new(dht.clone(), ServeConfig::default())?;
+        daemon.run(async {
+            tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+            Ok(())
+        }).await?;
+
+        // Queries that were handed to the node before the shutdown still go
+        // out.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Nobody ever responds, so every refresh bootstraps again.
+        let sent = dht.stats().queries_sent;
+        assert!(sent >= 10, "queries sent: {}", sent);
+
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        assert_eq!(dht.stats().queries_sent, sent);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_zero_periods() -> Result<()> {
+        let dht = Dht::builder(DhtConfig::default()).socket(KrpcSocketStub).build()?;
+
+        let err = Daemon::new(dht, ServeConfig {
+            ping_every: Duration::ZERO,
+            ..Default::default()
+        }).unwrap_err();
+
+        assert_eq!(err.to_string(), "ping period must not be zero");
+
+        Ok(())
+    }
+}
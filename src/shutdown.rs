@@ -0,0 +1,92 @@
+use anyhow::Result;
+use std::future::Future;
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
+
+/// Signal that asked us to shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+impl Signal {
+    /// Status to exit with, as a shell reports a process killed by the
+    /// signal.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Interrupt => 128 + 2,
+            Self::Terminate => 128 + 15,
+        }
+    }
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Interrupt => write!(f, "SIGINT"),
+            Self::Terminate => write!(f, "SIGTERM"),
+        }
+    }
+}
+
+/// Returned once everything was shut down after a signal, so the process
+/// exits with [`Signal::exit_code`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("interrupted by {0}")]
+pub struct Interrupted(pub Signal);
+
+/// Completes on the first SIGINT or SIGTERM.
+pub async fn wait_for_signal() -> Result<Signal> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    let signal = tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| Signal::Interrupt)?,
+        _ = terminate.recv() => Signal::Terminate,
+    };
+
+    Ok(signal)
+}
+
+/// Runs `task` until it's done, or fails with [`Interrupted`] on the first
+/// SIGINT or SIGTERM, whichever comes first.
+pub async fn until_signal<T, E>(task: impl Future<Output = Result<T, E>>) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    tokio::select! {
+        result = task => result.map_err(Into::into),
+
+        signal = wait_for_signal() => {
+            let signal = signal?;
+
+            info!(%signal, "shutting down");
+
+            Err(Interrupted(signal).into())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_with_status_of_signal() {
+        assert_eq!(Signal::Interrupt.exit_code(), 130);
+        assert_eq!(Signal::Terminate.exit_code(), 143);
+
+        let err = anyhow::Error::from(Interrupted(Signal::Terminate));
+        assert_eq!(err.to_string(), "interrupted by SIGTERM");
+        assert_eq!(err.downcast_ref(), Some(&Interrupted(Signal::Terminate)));
+    }
+
+    #[tokio::test]
+    async fn returns_result_of_task_that_finishes_first() {
+        assert_eq!(until_signal(async { Ok::<_, Interrupted>(1) }).await.unwrap(), 1);
+
+        let err = until_signal(async { Err::<(), _>(Interrupted(Signal::Interrupt)) }).await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Interrupted(Signal::Interrupt)));
+    }
+}
@@ -0,0 +1,621 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use futures::{FutureExt, StreamExt};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, info};
+
+use crate::dht::*;
+use crate::events::*;
+use crate::krpc::*;
+use crate::logging::*;
+use crate::serve::*;
+use crate::vnet::*;
+
+/// Log filter of the simulator when neither `--log-level` nor `RUST_LOG` is
+/// set. Thousands of nodes logging at `info` drown out everything else.
+pub const SIM_LOG_FILTER: &str = "warn,kademliar::sim=info";
+
+const REPORT_CSV_HEADER: &str = concat!(
+    "time_secs,nodes,joined,left,lookups,success_rate,mean_hops,max_hops,",
+    "mean_queries,mean_lookup_ms,datagrams,datagrams_per_node_sec,lost,",
+    "table_entries,stale_entries,staleness",
+);
+
+/// Simulates a network of many nodes on a virtual clock and reports how
+/// lookups and routing tables hold up, as CSV.
+#[derive(Debug, Parser, Clone)]
+#[command(name = "kademliar-sim", version)]
+pub struct SimArgs {
+    /// Nodes to join before measuring starts.
+    #[arg(long, default_value_t = 1_000)]
+    pub nodes: usize,
+
+    /// How many nodes to keep per k-bucket.
+    #[arg(short = 'k', default_value_t = 8)]
+    pub k: usize,
+
+    /// How many requests each node sends at once.
+    #[arg(short = 'j', long, default_value_t = 3)]
+    pub concurrency: usize,
+
+    /// Timeout for requests, in milliseconds.
+    #[arg(long, default_value_t = 5_000)]
+    pub timeout_ms: u64,
+
+    /// How many times to retry a request that timed out.
+    #[arg(long, default_value_t = 0)]
+    pub retries: u32,
+
+    /// Delay before the first retry, in milliseconds. Doubles with each
+    /// retry.
+    #[arg(long, default_value_t = 500)]
+    pub retry_backoff_ms: u64,
+
+    /// One-way delay of every datagram, in milliseconds.
+    #[arg(long, default_value_t = 50)]
+    pub latency_ms: u64,
+
+    /// Up to this much is added to the delay of each datagram, at random, in
+    /// milliseconds.
+    #[arg(long, default_value_t = 20)]
+    pub jitter_ms: u64,
+
+    /// Share of datagrams lost, from 0 to 1.
+    #[arg(long, default_value_t = 0.0)]
+    pub loss: f64,
+
+    /// New nodes joining per minute, on average.
+    #[arg(long, default_value_t = 10.0)]
+    pub joins_per_min: f64,
+
+    /// Nodes leaving per minute, on average. They leave without a word, like
+    /// most nodes do.
+    #[arg(long, default_value_t = 10.0)]
+    pub leaves_per_min: f64,
+
+    /// Lookups of a random node from another random node per minute, on
+    /// average.
+    #[arg(long, default_value_t = 60.0)]
+    pub lookups_per_min: f64,
+
+    /// How often each node refreshes buckets without good nodes, in seconds.
+    #[arg(long, default_value_t = 60)]
+    pub refresh_secs: u64,
+
+    /// How often each node pings nodes that haven't responded for a while,
+    /// in seconds.
+    #[arg(long, default_value_t = 5 * 60)]
+    pub ping_secs: u64,
+
+    /// How long to measure, in seconds.
+    #[arg(long, default_value_t = 10 * 60)]
+    pub duration_secs: u64,
+
+    /// Length of the interval each line of the report covers, in seconds.
+    #[arg(long, default_value_t = 60)]
+    pub report_secs: u64,
+
+    /// File to write the report to, or `-` for stdout.
+    #[arg(short, long, default_value = "-")]
+    pub out: PathBuf,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+impl SimArgs {
+    pub fn validate(&self) -> Result<()> {
+        if self.k == 0 || self.concurrency == 0 {
+            bail!("k and concurrency must be at least 1");
+        }
+
+        if !(0.0..=1.0).contains(&self.loss) {
+            bail!("loss must be between 0 and 1: {}", self.loss);
+        }
+
+        if self.report_secs == 0 {
+            bail!("report interval must be at least a second");
+        }
+
+        if self.timeout_ms == 0 {
+            bail!("timeout must be at least a millisecond");
+        }
+
+        if self.refresh_secs == 0 || self.ping_secs == 0 {
+            bail!("refresh and ping intervals must be at least a second");
+        }
+
+        Ok(())
+    }
+
+    fn dht_config(&self) -> DhtConfig {
+        DhtConfig {
+            k: self.k,
+            concurrency: self.concurrency,
+            timeout_ms: self.timeout_ms,
+            retries: self.retries,
+            retry_backoff_ms: self.retry_backoff_ms,
+            ..Default::default()
+        }
+    }
+
+    fn serve_config(&self) -> ServeConfig {
+        ServeConfig {
+            refresh_every: Duration::from_secs(self.refresh_secs),
+            ping_every: Duration::from_secs(self.ping_secs),
+            ..Default::default()
+        }
+    }
+
+    fn link(&self) -> VirtualLink {
+        VirtualLink {
+            latency: Duration::from_millis(self.latency_ms),
+            jitter: Duration::from_millis(self.jitter_ms),
+            loss: self.loss,
+        }
+    }
+}
+
+/// Runs the simulation described by the command line. The clock starts
+/// paused and only advances when every node is waiting, so a simulated hour
+/// takes as long as the work done in it.
+pub fn main() -> Result<()> {
+    let args = SimArgs::parse();
+
+    args.log.init_or(SIM_LOG_FILTER)?;
+    args.validate()?;
+
+    let mut out: Box<dyn Write> = if args.out == Path::new("-") {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(
+            std::fs::File::create(&args.out)
+                .with_context(|| format!("failed to create: {:?}", args.out))?
+        )
+    };
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?
+        .block_on(Simulation::new(args).run(&mut out))
+}
+
+/// A node on the simulated network, along with the task keeping its routing
+/// table fresh.
+#[derive(Debug)]
+struct SimNode {
+    dht: Dht,
+    addr: NodeAddr,
+    task: JoinHandle<()>,
+}
+
+/// What happened since the last report.
+#[derive(Debug, Default)]
+struct IntervalStats {
+    joined: usize,
+    left: usize,
+    lookups: usize,
+    succeeded: usize,
+
+    /// Lookups that got to query at least one node. Hops, queries and time
+    /// are summed up over these.
+    finished: usize,
+    hops: usize,
+    max_hops: usize,
+    queries: usize,
+    lookup_time: Duration,
+}
+
+/// One line of the report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportRow {
+    pub time_secs: u64,
+    pub nodes: usize,
+    pub joined: usize,
+    pub left: usize,
+    pub lookups: usize,
+
+    /// Share of lookups that found the node they looked for.
+    pub success_rate: f64,
+    pub mean_hops: f64,
+    pub max_hops: usize,
+
+    /// Queries sent per lookup.
+    pub mean_queries: f64,
+    pub mean_lookup_ms: f64,
+
+    /// Datagrams sent by all nodes, for lookups and everything else.
+    pub datagrams: u64,
+    pub datagrams_per_node_sec: f64,
+
+    /// Datagrams lost on the way, see `--loss`.
+    pub lost: u64,
+
+    /// Nodes in all routing tables, and how many of those left already.
+    pub table_entries: usize,
+    pub stale_entries: usize,
+    pub staleness: f64,
+}
+
+impl ReportRow {
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{:.3},{:.3},{},{:.3},{:.3},{},{:.3},{},{},{},{:.3}",
+            self.time_secs,
+            self.nodes,
+            self.joined,
+            self.left,
+            self.lookups,
+            self.success_rate,
+            self.mean_hops,
+            self.max_hops,
+            self.mean_queries,
+            self.mean_lookup_ms,
+            self.datagrams,
+            self.datagrams_per_node_sec,
+            self.lost,
+            self.table_entries,
+            self.stale_entries,
+            self.staleness,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Simulation {
+    args: SimArgs,
+    network: VirtualNetwork,
+    nodes: Vec<SimNode>,
+    stats: Arc<Mutex<IntervalStats>>,
+
+    /// Network counters at the last report.
+    datagrams_sent: u64,
+    datagrams_lost: u64,
+}
+
+impl Simulation {
+    pub fn new(args: SimArgs) -> Self {
+        Self {
+            network: VirtualNetwork::with_link(args.link()),
+            nodes: vec![],
+            stats: Arc::default(),
+            datagrams_sent: 0,
+            datagrams_lost: 0,
+            args,
+        }
+    }
+
+    /// Joins the initial nodes one after the other, then runs the churn and
+    /// lookups for `--duration-secs`. Writes a line to `out` every
+    /// `--report-secs`.
+    pub async fn run(mut self, out: &mut impl Write) -> Result<()> {
+        let started_at = Instant::now();
+
+        for _ in 0..self.args.nodes {
+            let _ = self.join()?.await;
+        }
+
+        info!(
+            nodes = self.nodes.len(),
+            elapsed = ?started_at.elapsed(),
+            "sim: joined"
+        );
+
+        // Only what happens from now on is measured.
+        self.report(Duration::ZERO).await;
+
+        writeln!(out, "{}", REPORT_CSV_HEADER)?;
+
+        let started_at = Instant::now();
+        let report_every = Duration::from_secs(self.args.report_secs);
+        let end = started_at + Duration::from_secs(self.args.duration_secs);
+
+        let mut next_report = started_at + report_every;
+        let mut next_join = next_event(self.args.joins_per_min);
+        let mut next_leave = next_event(self.args.leaves_per_min);
+        let mut next_lookup = next_event(self.args.lookups_per_min);
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next_report) => {
+                    let row = self.report(started_at.elapsed()).await;
+
+                    info!(
+                        time_secs = row.time_secs,
+                        nodes = row.nodes,
+                        success_rate = row.success_rate,
+                        "sim: report"
+                    );
+
+                    writeln!(out, "{}", row.to_csv())?;
+                    out.flush()?;
+
+                    if next_report >= end {
+                        break;
+                    }
+
+                    next_report += report_every;
+                },
+
+                _ = at(next_join) => {
+                    self.join()?;
+                    next_join = next_event(self.args.joins_per_min);
+                },
+
+                _ = at(next_leave) => {
+                    self.leave().await;
+                    next_leave = next_event(self.args.leaves_per_min);
+                },
+
+                _ = at(next_lookup) => {
+                    self.lookup();
+                    next_lookup = next_event(self.args.lookups_per_min);
+                },
+            }
+        }
+
+        for node in self.nodes.drain(..) {
+            node.task.abort();
+            node.dht.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a node that bootstraps off a random node already on the
+    /// network, then keeps its routing table fresh. Returns when it's done
+    /// bootstrapping.
+    fn join(&mut self) -> Result<oneshot::Receiver<()>> {
+        let sock = self.network.bind_next();
+        let addr = sock.addr().clone();
+
+        let dht = Dht::builder(DhtConfig {
+            bootstrap_nodes: self
+                .nodes
+                .choose(&mut rand::thread_rng())
+                .map(|x| x.addr.clone().into())
+                .into_iter()
+                .collect(),
+            ..self.args.dht_config()
+        })
+        .socket(sock)
+        .build()?;
+
+        let (joined_tx, joined_rx) = oneshot::channel();
+
+        let mut daemon = Daemon::new(dht.clone(), self.args.serve_config())?;
+
+        let task = tokio::spawn({
+            let dht = dht.clone();
+
+            async move {
+                if let Err(e) = dht.bootstrap().await {
+                    debug!(id = %dht.id(), err = ?e, "sim: bootstrap failed");
+                }
+
+                let _ = joined_tx.send(());
+                let _ = daemon.run(futures::future::pending::<Result<()>>()).await;
+            }
+        });
+
+        self.nodes.push(SimNode { dht, addr, task });
+        self.stats.lock().unwrap().joined += 1;
+
+        Ok(joined_rx)
+    }
+
+    /// Stops a random node.
+    async fn leave(&mut self) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let index = rand::thread_rng().gen_range(0..self.nodes.len());
+        let node = self.nodes.swap_remove(index);
+
+        node.task.abort();
+        node.dht.shutdown().await;
+
+        self.stats.lock().unwrap().left += 1;
+    }
+
+    /// Looks up a random node from another random node, in the background.
+    fn lookup(&self) {
+        let [src, dst] = self.nodes.choose_multiple(&mut rand::thread_rng(), 2).collect::<Vec<_>>()[..] else {
+            return;
+        };
+
+        let dht = src.dht.clone();
+        let target = dst.dht.id().clone();
+        let stats = self.stats.clone();
+
+        tokio::spawn(async move {
+            let mut events = Box::pin(dht.events());
+
+            let found = match dht.find_node(target.clone()).await {
+                Ok(nodes) => nodes.iter().any(|x| x.id == target),
+                Err(DhtError::Shutdown) => return,
+                Err(_) => false,
+            };
+
+            // Emitted before `find_node` returned, so it's waiting already.
+            let finished = std::iter::from_fn(|| events.next().now_or_never().flatten())
+                .find_map(|event| match event {
+                    DhtEvent::LookupFinished { target: x, queried, hops, elapsed, .. } if x == target => {
+                        Some((queried, hops, elapsed))
+                    },
+                    _ => None,
+                });
+
+            let mut stats = stats.lock().unwrap();
+
+            stats.lookups += 1;
+            stats.succeeded += usize::from(found);
+
+            if let Some((queried, hops, elapsed)) = finished {
+                stats.finished += 1;
+                stats.hops += hops;
+                stats.max_hops = stats.max_hops.max(hops);
+                stats.queries += queried;
+                stats.lookup_time += elapsed;
+            }
+        });
+    }
+
+    /// Sums up what happened since the last report and starts over.
+    async fn report(&mut self, elapsed: Duration) -> ReportRow {
+        let live: HashSet<NodeId> = self.nodes.iter().map(|x| x.dht.id().clone()).collect();
+        let mut table_entries = 0;
+        let mut stale_entries = 0;
+
+        for node in self.nodes.iter() {
+            let table = node.dht.routing_table().await;
+
+            table_entries += table.len();
+            stale_entries += table.nodes().filter(|x| !live.contains(&x.id)).count();
+        }
+
+        let stats = std::mem::take(&mut *self.stats.lock().unwrap());
+
+        let network = self.network.stats();
+        let datagrams_sent = network.datagrams_sent.load(Ordering::Relaxed);
+        let datagrams_lost = network.datagrams_lost.load(Ordering::Relaxed);
+        let datagrams = datagrams_sent - std::mem::replace(&mut self.datagrams_sent, datagrams_sent);
+        let lost = datagrams_lost - std::mem::replace(&mut self.datagrams_lost, datagrams_lost);
+
+        let ratio = |x: f64, total: usize| if total == 0 { 0.0 } else { x / total as f64 };
+
+        ReportRow {
+            time_secs: elapsed.as_secs(),
+            nodes: self.nodes.len(),
+            joined: stats.joined,
+            left: stats.left,
+            lookups: stats.lookups,
+            success_rate: ratio(stats.succeeded as f64, stats.lookups),
+            mean_hops: ratio(stats.hops as f64, stats.finished),
+            max_hops: stats.max_hops,
+            mean_queries: ratio(stats.queries as f64, stats.finished),
+            mean_lookup_ms: ratio(stats.lookup_time.as_secs_f64() * 1_000.0, stats.finished),
+            datagrams,
+            datagrams_per_node_sec: ratio(datagrams as f64 / self.args.report_secs as f64, self.nodes.len()),
+            lost,
+            table_entries,
+            stale_entries,
+            staleness: ratio(stale_entries as f64, table_entries),
+        }
+    }
+}
+
+/// When the next of the events happening `per_min` times a minute on average
+/// is due. Never, if `per_min` is zero.
+fn next_event(per_min: f64) -> Option<Instant> {
+    if per_min <= 0.0 {
+        return None;
+    }
+
+    // Time between events of a Poisson process is exponentially distributed.
+    let uniform: f64 = rand::thread_rng().gen();
+    let secs = -(1.0 - uniform).ln() * 60.0 / per_min;
+
+    Some(Instant::now() + Duration::from_secs_f64(secs))
+}
+
+/// Completes at `deadline`, or never if there is none.
+async fn at(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(extra: &[&str]) -> SimArgs {
+        SimArgs::parse_from(["kademliar-sim"].iter().chain(extra))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_lookups_under_churn() -> Result<()> {
+        let args = args(&[
+            "--nodes", "100",
+            "--duration-secs", "120",
+            "--lookups-per-min", "60",
+            "--joins-per-min", "5",
+            "--leaves-per-min", "5",
+        ]);
+
+        let mut out = vec![];
+        Simulation::new(args).run(&mut out).await?;
+
+        let out = String::from_utf8(out)?;
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 3, "{}", out);
+        assert_eq!(lines[0], REPORT_CSV_HEADER);
+
+        for line in lines[1..].iter() {
+            let columns: Vec<&str> = line.split(',').collect();
+            let column = |name: &str| -> f64 {
+                let index = REPORT_CSV_HEADER.split(',').position(|x| x == name).unwrap();
+                columns[index].parse().unwrap()
+            };
+
+            assert_eq!(columns.len(), REPORT_CSV_HEADER.split(',').count());
+            assert!(column("lookups") > 0.0, "{}", line);
+            assert!(column("success_rate") >= 0.8, "{}", line);
+            assert!(column("mean_hops") >= 1.0, "{}", line);
+            assert!(column("table_entries") > 0.0, "{}", line);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(args(&[]).validate().is_ok());
+        assert!(args(&["--loss", "1.5"]).validate().is_err());
+        assert!(args(&["-k", "0"]).validate().is_err());
+        assert!(args(&["--timeout-ms", "0"]).validate().is_err());
+        assert!(args(&["--refresh-secs", "0"]).validate().is_err());
+        assert!(args(&["--ping-secs", "0"]).validate().is_err());
+    }
+
+    #[test]
+    fn formats_report_row_as_csv() {
+        let row = ReportRow {
+            time_secs: 60,
+            nodes: 10,
+            joined: 1,
+            left: 2,
+            lookups: 4,
+            success_rate: 0.75,
+            mean_hops: 2.5,
+            max_hops: 4,
+            mean_queries: 12.0,
+            mean_lookup_ms: 310.25,
+            datagrams: 600,
+            datagrams_per_node_sec: 1.0,
+            lost: 0,
+            table_entries: 80,
+            stale_entries: 8,
+            staleness: 0.1,
+        };
+
+        assert_eq!(
+            row.to_csv(),
+            "60,10,1,2,4,0.750,2.500,4,12.000,310.250,600,1.000,0,80,8,0.100"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tokio_util::time::{delay_queue, DelayQueue};
+use tracing::debug;
+
+use crate::krpc::*;
+
+#[derive(Debug)]
+enum TimeoutCommand {
+    Start(TxId, Duration, Deadline),
+    Cancel(TxId),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Deadline {
+    /// Sends `KrpcMessage::ResponseTimeout`.
+    Response,
+
+    /// Sends `KrpcMessage::Request`, for requests that are retried later.
+    Retry,
+}
+
+/// Delay before retrying a request for the given attempt, doubling with each
+/// attempt.
+pub fn retry_backoff(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt))
+}
+
+/// Owns the deadlines of all in-flight requests in a single timer wheel.
+/// Sends `KrpcMessage::ResponseTimeout` to the main loop for every request
+/// that wasn't cancelled before its deadline. The task stops once every
+/// handle is dropped.
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    cmd_tx: mpsc::UnboundedSender<TimeoutCommand>,
+}
+
+impl RequestTimeouts {
+    pub fn spawn(main_tx: mpsc::Sender<KrpcMessage>) -> (Self, JoinHandle<()>) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(Self::run(cmd_rx, main_tx));
+
+        (Self { cmd_tx }, task)
+    }
+
+    /// Starts (or restarts) the deadline of a request.
+    pub fn start(&self, tx_id: TxId, timeout: Duration) {
+        let _ = self.cmd_tx.send(
+            TimeoutCommand::Start(tx_id, timeout, Deadline::Response)
+        );
+    }
+
+    /// Hands the request back to the main loop as `KrpcMessage::Request`
+    /// once `delay` has passed.
+    pub fn delay_request(&self, tx_id: TxId, delay: Duration) {
+        let _ = self.cmd_tx.send(
+            TimeoutCommand::Start(tx_id, delay, Deadline::Retry)
+        );
+    }
+
+    pub fn cancel(&self, tx_id: &TxId) {
+        let _ = self.cmd_tx.send(TimeoutCommand::Cancel(tx_id.clone()));
+    }
+
+    async fn run(
+        mut cmd_rx: mpsc::UnboundedReceiver<TimeoutCommand>,
+        main_tx: mpsc::Sender<KrpcMessage>,
+    ) {
+        let mut queue: DelayQueue<(TxId, Deadline)> = DelayQueue::new();
+        let mut keys: HashMap<TxId, delay_queue::Key> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(TimeoutCommand::Start(tx_id, timeout, deadline)) => {
+                        if let Some(key) = keys.remove(&tx_id) {
+                            queue.remove(&key);
+                        }
+
+                        let key = queue.insert((tx_id.clone(), deadline), timeout);
+                        keys.insert(tx_id, key);
+                    },
+
+                    Some(TimeoutCommand::Cancel(tx_id)) => {
+                        if let Some(key) = keys.remove(&tx_id) {
+                            queue.remove(&key);
+                        }
+                    },
+
+                    None => break,
+                },
+
+                Some(expired) = queue.next(), if !queue.is_empty() => {
+                    let (tx_id, deadline) = expired.into_inner();
+
+                    keys.remove(&tx_id);
+
+                    let msg = match deadline {
+                        Deadline::Response => KrpcMessage::ResponseTimeout(tx_id),
+                        Deadline::Retry => KrpcMessage::Request(tx_id),
+                    };
+
+                    if main_tx.send(msg).await.is_err() {
+                        break;
+                    }
+                },
+            }
+        }
+
+        debug!("timeouts: exit");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_only_requests_that_were_not_cancelled() -> anyhow::Result<()> {
+        let (main_tx, mut main_rx) = mpsc::channel::<KrpcMessage>(1024);
+        let (timeouts, _task) = RequestTimeouts::spawn(main_tx);
+
+        timeouts.start(TxId::from_str("aa")?, Duration::from_millis(100));
+        timeouts.start(TxId::from_str("ab")?, Duration::from_millis(200));
+        timeouts.cancel(&TxId::from_str("aa")?);
+
+        assert!(matches!(
+            main_rx.recv().await,
+            Some(KrpcMessage::ResponseTimeout(tx_id)) if tx_id == TxId::from_str("ab")?
+        ));
+
+        drop(timeouts);
+
+        assert!(main_rx.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn doubles_retry_backoff_per_attempt() {
+        let base = Duration::from_millis(100);
+
+        assert_eq!(retry_backoff(base, 0), Duration::from_millis(100));
+        assert_eq!(retry_backoff(base, 1), Duration::from_millis(200));
+        assert_eq!(retry_backoff(base, 3), Duration::from_millis(800));
+    }
+}
@@ -0,0 +1,345 @@
+use async_trait::async_trait;
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+use crate::krpc::*;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// First address handed out by [`VirtualNetwork::bind_next`].
+pub const FIRST_VIRTUAL_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+/// Port of every address handed out by [`VirtualNetwork::bind_next`].
+pub const VIRTUAL_PORT: u16 = 6881;
+
+/// How datagrams travel on a [`VirtualNetwork`]. The default delivers them
+/// right away and never loses one.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualLink {
+    /// Delay of every datagram.
+    pub latency: Duration,
+
+    /// Up to this much is added to `latency`, at random per datagram, so
+    /// datagrams may overtake each other.
+    pub jitter: Duration,
+
+    /// Chance of a datagram getting lost, from 0 to 1.
+    pub loss: f64,
+}
+
+/// In-process network for running many nodes without real sockets.
+/// Datagrams between sockets bound on the same network are delivered through
+/// channels, as configured by its [`VirtualLink`]. Datagrams to addresses
+/// nobody is bound to are dropped, as UDP would.
+///
+/// Clones share the same network.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualNetwork(Arc<VirtualNetworkInner>);
+
+#[derive(Debug, Default)]
+struct VirtualNetworkInner {
+    link: VirtualLink,
+    inboxes: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>>,
+    next_ip: AtomicU32,
+    stats: VirtualNetworkStats,
+}
+
+#[derive(Debug, Default)]
+pub struct VirtualNetworkStats {
+    pub datagrams_sent: AtomicU64,
+    pub datagrams_delivered: AtomicU64,
+
+    /// Datagrams lost on the link. The ones sent to addresses nobody is bound
+    /// to aren't counted.
+    pub datagrams_lost: AtomicU64,
+
+    pub bytes_sent: AtomicU64,
+}
+
+impl VirtualNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_link(link: VirtualLink) -> Self {
+        Self(Arc::new(VirtualNetworkInner {
+            link,
+            ..Default::default()
+        }))
+    }
+
+    /// Binds a socket to `addr`. Fails if one is bound to it already.
+    pub fn bind(&self, addr: NodeAddr) -> io::Result<VirtualSocket> {
+        let key = SocketAddr::from((addr.ip, addr.port));
+        let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+
+        let mut inboxes = self.0.inboxes.lock().unwrap();
+
+        if inboxes.contains_key(&key) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("already bound: {}", addr)));
+        }
+
+        inboxes.insert(key, inbox_tx);
+
+        Ok(VirtualSocket {
+            addr,
+            network: self.clone(),
+            inbox: tokio::sync::Mutex::new(inbox_rx),
+        })
+    }
+
+    /// Binds a socket to the next unused address, counting up from
+    /// [`FIRST_VIRTUAL_IP`].
+    pub fn bind_next(&self) -> VirtualSocket {
+        loop {
+            let offset = self.0.next_ip.fetch_add(1, Ordering::Relaxed);
+            let ip = Ipv4Addr::from(u32::from(FIRST_VIRTUAL_IP).wrapping_add(offset));
+
+            if let Ok(sock) = self.bind(NodeAddr::new(ip, VIRTUAL_PORT)) {
+                return sock;
+            }
+        }
+    }
+
+    /// Number of sockets bound.
+    pub fn len(&self) -> usize {
+        self.0.inboxes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> &VirtualNetworkStats {
+        &self.0.stats
+    }
+
+    fn send(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let VirtualLink { latency, jitter, loss } = self.0.link;
+        let stats = &self.0.stats;
+
+        stats.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        let mut rng = rand::thread_rng();
+
+        if loss > 0.0 && rng.gen_bool(loss.min(1.0)) {
+            stats.datagrams_lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let delay = latency + jitter.mul_f64(rng.gen());
+
+        if delay.is_zero() {
+            return self.deliver(dst, (data.to_vec(), src));
+        }
+
+        // The receiver may be gone by the time the datagram arrives, so it's
+        // looked up only then.
+        let network = self.clone();
+        let datagram = (data.to_vec(), src);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            network.deliver(dst, datagram);
+        });
+    }
+
+    fn deliver(&self, dst: SocketAddr, datagram: Datagram) {
+        let inboxes = self.0.inboxes.lock().unwrap();
+
+        if let Some(inbox) = inboxes.get(&dst) {
+            if inbox.send(datagram).is_ok() {
+                self.0.stats.datagrams_delivered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn unbind(&self, addr: &NodeAddr) {
+        self.0.inboxes.lock().unwrap().remove(&SocketAddr::from((addr.ip, addr.port)));
+    }
+}
+
+/// Socket bound on a [`VirtualNetwork`]. Unbound once dropped.
+#[derive(Debug)]
+pub struct VirtualSocket {
+    addr: NodeAddr,
+    network: VirtualNetwork,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl VirtualSocket {
+    pub fn addr(&self) -> &NodeAddr {
+        &self.addr
+    }
+}
+
+impl Drop for VirtualSocket {
+    fn drop(&mut self) {
+        self.network.unbind(&self.addr);
+    }
+}
+
+#[async_trait]
+impl KrpcSocket for VirtualSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> tokio::io::Result<(usize, core::net::SocketAddr)> {
+        let Some((data, src)) = self.inbox.lock().await.recv().await else {
+            return futures::future::pending().await;
+        };
+
+        // Like UDP, whatever doesn't fit into `buf` is lost.
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        Ok((len, src))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: String) -> tokio::io::Result<usize> {
+        let dst = target
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address: {}", target)))?;
+
+        self.network.send(SocketAddr::from((self.addr.ip, self.addr.port)), dst, buf);
+
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::*;
+    use crate::serve::*;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn delivers_datagrams_between_bound_sockets() -> anyhow::Result<()> {
+        let network = VirtualNetwork::new();
+        let a = network.bind_next();
+        let b = network.bind_next();
+
+        assert_eq!(a.addr().to_string(), "10.0.0.1:6881");
+        assert_eq!(b.addr().to_string(), "10.0.0.2:6881");
+        assert!(network.bind(a.addr().clone()).is_err());
+
+        a.send_to(b"hello", b.addr().to_string()).await?;
+        a.send_to(b"nobody", "10.0.0.3:6881".into()).await?;
+
+        let mut buf = [0; 4];
+        let (len, src) = b.recv_from(&mut buf).await?;
+
+        assert_eq!(&buf[..len], b"hell");
+        assert_eq!(src.to_string(), "10.0.0.1:6881");
+
+        drop(b);
+        assert_eq!(network.len(), 1);
+
+        let stats = network.stats();
+        assert_eq!(stats.datagrams_sent.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.datagrams_delivered.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_and_loses_datagrams_as_configured() -> anyhow::Result<()> {
+        let network = VirtualNetwork::with_link(VirtualLink {
+            latency: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        let a = network.bind_next();
+        let b = network.bind_next();
+        let started_at = tokio::time::Instant::now();
+
+        a.send_to(b"hello", b.addr().to_string()).await?;
+
+        let mut buf = [0; 16];
+        b.recv_from(&mut buf).await?;
+
+        assert_eq!(started_at.elapsed(), Duration::from_millis(100));
+
+        let network = VirtualNetwork::with_link(VirtualLink { loss: 1.0, ..Default::default() });
+        let a = network.bind_next();
+
+        a.send_to(b"hello", a.addr().to_string()).await?;
+
+        assert_eq!(network.stats().datagrams_lost.load(Ordering::Relaxed), 1);
+        assert_eq!(network.stats().datagrams_delivered.load(Ordering::Relaxed), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn converges_and_finds_closest_nodes() -> anyhow::Result<()> {
+        const NODES: usize = 200;
+
+        let network = VirtualNetwork::new();
+        let mut dhts = vec![];
+        let mut first = None;
+
+        for _ in 0..NODES {
+            let sock = network.bind_next();
+            let addr = sock.addr().clone();
+
+            let dht = Dht::builder(DhtConfig {
+                bootstrap_nodes: first.iter().cloned().collect(),
+                ..Default::default()
+            })
+            .socket(sock)
+            .build()?;
+
+            first.get_or_insert_with(|| addr.into());
+            dhts.push(dht);
+        }
+
+        for dht in dhts.iter().skip(1) {
+            assert!(dht.bootstrap().await? > 0);
+        }
+
+        // Looking up our own id only fills the buckets close to it. Refresh
+        // the others, as `serve` does.
+        for dht in dhts.iter() {
+            Daemon::new(dht.clone(), ServeConfig::default())?.refresh_buckets().await;
+        }
+
+        let everyone: Vec<NodeId> = dhts.iter().map(|x| x.id().clone()).collect();
+
+        for dht in dhts.iter() {
+            let table = dht.routing_table().await;
+            let closest = closest_ids(&everyone, dht.id(), dht.id(), 1);
+
+            assert!(table.len() >= table.k(), "{} knows {} nodes", dht.id(), table.len());
+            assert!(table.contains(&closest[0]), "{} doesn't know its neighbour", dht.id());
+        }
+
+        for (index, dht) in dhts.iter().enumerate().step_by(10) {
+            let target = &everyone[(index + NODES / 2) % NODES];
+            let found: HashSet<NodeId> = dht.find_node(target.clone()).await?.into_iter().map(|x| x.id).collect();
+            let expected = closest_ids(&everyone, target, dht.id(), DhtConfig::default().k);
+
+            assert!(found.contains(target));
+            assert_eq!(found, expected.into_iter().collect());
+        }
+
+        for dht in dhts.iter() {
+            dht.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    /// The `count` ids closest to `target`, other than `own`.
+    fn closest_ids(ids: &[NodeId], target: &NodeId, own: &NodeId, count: usize) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = ids.iter().filter(|x| *x != own).cloned().collect();
+        ids.sort_by_key(|x| x.distance_to(target));
+        ids.truncate(count);
+        ids
+    }
+}